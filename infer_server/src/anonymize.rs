@@ -0,0 +1,265 @@
+//! Anonymization of detected regions, e.g. for privacy-compliant recordings of faces.
+//!
+use std::{collections::HashMap, path::Path};
+
+use anyhow::{bail, Result};
+use image::{imageops, Rgb, RgbImage};
+use imageproc::{drawing::draw_filled_rect_mut, filter::gaussian_blur_f32, rect::Rect};
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    nn::{Bbox, Detection},
+    utils::read_stream_configs,
+};
+
+/// Color of filled regions.
+const FILL_COLOR: [u8; 3] = [0, 0, 0];
+/// Minimum blur radius and block size relative to the larger side of a region.
+///
+/// Weaker settings would leave faces recognizable, whatever the configured strength.
+const MIN_RELATIVE_STRENGTH: f32 = 1.0 / 8.0;
+
+/// How detected regions are made unrecognizable.
+#[derive(Clone, Copy, Debug, Default, Deserialize, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum AnonymizeMode {
+    /// Gaussian blur.
+    #[default]
+    Blur,
+    /// Coarse blocks of the average color.
+    Pixelate,
+    /// Solid black fill.
+    Fill,
+}
+
+/// Anonymization of the detected regions of a stream.
+#[derive(Clone, Copy, Debug, Deserialize, PartialEq, Serialize)]
+#[serde(default)]
+pub struct Anonymization {
+    pub mode: AnonymizeMode,
+    /// Strength between 0 (exclusive) and 1, relative to the size of a region.
+    ///
+    /// Scales the blur radius or the size of the blocks, filling ignores it. Both are at least
+    /// an eighth of the region.
+    pub strength: f32,
+}
+
+impl Default for Anonymization {
+    fn default() -> Self {
+        Self {
+            mode: AnonymizeMode::default(),
+            strength: 0.5,
+        }
+    }
+}
+
+impl Anonymization {
+    pub fn new(mode: AnonymizeMode, strength: f32) -> Result<Self> {
+        let anonymization = Self { mode, strength };
+        anonymization.validate()?;
+
+        Ok(anonymization)
+    }
+
+    pub fn validate(&self) -> Result<()> {
+        if !(self.strength > 0.0 && self.strength <= 1.0) {
+            bail!(
+                "anonymization strength has to be in (0, 1], got {}",
+                self.strength
+            );
+        }
+
+        Ok(())
+    }
+
+    /// Blur radius or block size for a region with the given larger side.
+    fn region_strength(&self, size: u32) -> f32 {
+        let size = size as f32;
+        (self.strength * size / 4.0).max(size * MIN_RELATIVE_STRENGTH)
+    }
+
+    /// Anonymize the regions of all detections in the image.
+    pub fn apply(&self, frame: &mut RgbImage, detections: &[Detection]) {
+        for detection in detections {
+            let Some((x, y, width, height)) = pixel_region(&detection.bbox, frame) else {
+                continue;
+            };
+
+            match self.mode {
+                AnonymizeMode::Blur => {
+                    let region = imageops::crop_imm(frame, x, y, width, height).to_image();
+                    let sigma = self.region_strength(width.max(height)).max(1.0);
+                    let blurred = gaussian_blur_f32(&region, sigma);
+                    imageops::replace(frame, &blurred, x as i64, y as i64);
+                }
+                AnonymizeMode::Pixelate => {
+                    // Blocks of a single pixel would leave the region unchanged
+                    let block_size =
+                        (self.region_strength(width.max(height)).round() as u32).max(2);
+                    pixelate(frame, (x, y, width, height), block_size);
+                }
+                AnonymizeMode::Fill => {
+                    let rect = Rect::at(x as i32, y as i32).of_size(width, height);
+                    draw_filled_rect_mut(frame, rect, Rgb(FILL_COLOR));
+                }
+            }
+        }
+    }
+}
+
+/// Read the anonymization of several streams by name from a TOML file.
+///
+/// ```toml
+/// [hallway]
+/// mode = "pixelate"
+/// strength = 0.75
+/// ```
+pub fn read_anonymization_file(path: impl AsRef<Path>) -> Result<HashMap<String, Anonymization>> {
+    read_stream_configs(path, "anonymization", Anonymization::validate)
+}
+
+/// Pixel region `(x, y, width, height)` of a bounding box, clamped to the image.
+fn pixel_region(bbox: &Bbox, frame: &RgbImage) -> Option<(u32, u32, u32, u32)> {
+    let to_px = |rel: f32, max: u32| (rel * max as f32).round().clamp(0.0, max as f32) as u32;
+    let (x_tl, y_tl) = (
+        to_px(bbox[0], frame.width()),
+        to_px(bbox[1], frame.height()),
+    );
+    let (x_br, y_br) = (
+        to_px(bbox[2], frame.width()),
+        to_px(bbox[3], frame.height()),
+    );
+
+    (x_br > x_tl && y_br > y_tl).then_some((x_tl, y_tl, x_br - x_tl, y_br - y_tl))
+}
+
+/// Replace blocks of the region by their average color.
+fn pixelate(frame: &mut RgbImage, region: (u32, u32, u32, u32), block_size: u32) {
+    let (x, y, width, height) = region;
+
+    for block_y in (y..y + height).step_by(block_size as usize) {
+        for block_x in (x..x + width).step_by(block_size as usize) {
+            let block_width = block_size.min(x + width - block_x);
+            let block_height = block_size.min(y + height - block_y);
+
+            let mut sum = [0u64; 3];
+            for py in block_y..block_y + block_height {
+                for px in block_x..block_x + block_width {
+                    for (channel, value) in sum.iter_mut().zip(frame.get_pixel(px, py).0) {
+                        *channel += value as u64;
+                    }
+                }
+            }
+            let count = (block_width * block_height) as u64;
+            let average = Rgb(sum.map(|channel| (channel / count) as u8));
+
+            for py in block_y..block_y + block_height {
+                for px in block_x..block_x + block_width {
+                    frame.put_pixel(px, py, average);
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+
+    use super::*;
+
+    /// Image with a checkerboard pattern of single pixels.
+    fn checkerboard() -> RgbImage {
+        RgbImage::from_fn(16, 16, |x, y| {
+            if (x + y) % 2 == 0 {
+                Rgb([255, 255, 255])
+            } else {
+                Rgb([0, 0, 0])
+            }
+        })
+    }
+
+    fn face() -> Detection {
+        Detection::new(0, "face", 0.9, [0.25, 0.25, 0.75, 0.75])
+    }
+
+    #[test]
+    fn test_anonymize_modes() {
+        for mode in [
+            AnonymizeMode::Blur,
+            AnonymizeMode::Pixelate,
+            AnonymizeMode::Fill,
+        ] {
+            let mut frame = checkerboard();
+            Anonymization::new(mode, 1.0)
+                .unwrap()
+                .apply(&mut frame, &[face()]);
+
+            // The pattern inside the region is gone, the outside is untouched
+            assert_ne!(
+                frame.get_pixel(7, 7),
+                checkerboard().get_pixel(7, 7),
+                "{mode:?}"
+            );
+            assert_eq!(frame.get_pixel(0, 0), checkerboard().get_pixel(0, 0));
+            assert_eq!(frame.get_pixel(15, 15), checkerboard().get_pixel(15, 15));
+        }
+    }
+
+    #[test]
+    fn test_low_strength_changes_every_pixel() {
+        for mode in [AnonymizeMode::Blur, AnonymizeMode::Pixelate] {
+            let mut frame = checkerboard();
+            Anonymization::new(mode, 0.001)
+                .unwrap()
+                .apply(&mut frame, &[face()]);
+
+            for (x, y, pixel) in checkerboard().enumerate_pixels() {
+                if (4..12).contains(&x) && (4..12).contains(&y) {
+                    assert_ne!(frame.get_pixel(x, y), pixel, "{mode:?} at {x}, {y}");
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn test_anonymization_defaults() -> Result<()> {
+        let anonymization: Anonymization = toml::from_str("mode = \"pixelate\"")?;
+        assert_eq!(
+            anonymization,
+            Anonymization::new(AnonymizeMode::Pixelate, 0.5)?
+        );
+
+        let anonymization: Anonymization = toml::from_str("strength = 0.0")?;
+        assert!(anonymization.validate().is_err());
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_pixelate_blocks() {
+        let mut frame = checkerboard();
+        pixelate(&mut frame, (4, 4, 8, 8), 2);
+
+        // Every 2x2 block of the checkerboard averages to gray
+        assert_eq!(*frame.get_pixel(4, 4), Rgb([127, 127, 127]));
+        assert_eq!(*frame.get_pixel(11, 11), Rgb([127, 127, 127]));
+        assert_eq!(frame.get_pixel(3, 3), checkerboard().get_pixel(3, 3));
+    }
+
+    #[test]
+    fn test_pixel_region_clamped() {
+        let frame = checkerboard();
+        assert_eq!(
+            pixel_region(&[-0.5, 0.5, 0.5, 1.5], &frame),
+            Some((0, 8, 8, 8))
+        );
+        assert_eq!(pixel_region(&[0.5, 0.5, 0.5, 0.75], &frame), None);
+    }
+
+    #[test]
+    fn test_anonymization_validation() {
+        assert!(Anonymization::new(AnonymizeMode::Blur, 0.0).is_err());
+        assert!(Anonymization::new(AnonymizeMode::Pixelate, 1.5).is_err());
+        assert!(Anonymization::new(AnonymizeMode::Fill, 1.0).is_ok());
+    }
+}
@@ -1,18 +1,42 @@
 //! Infer server binary.
 //!
-use std::{net::SocketAddr, sync::Arc};
+use std::{net::SocketAddr, path::PathBuf, sync::Arc, time::Duration};
 
-use anyhow::Result;
+use anyhow::{bail, Result};
 use argh::FromArgs;
-use axum::{routing::get, Extension, Router};
+use axum::{
+    extract::DefaultBodyLimit,
+    routing::{get, post},
+    Extension, Router,
+};
 use env_logger::TimestampPrecision;
 use infer_server::{
+    anonymize::read_anonymization_file,
+    clips::{
+        remove_partial_clips, spawn_clip_recorder, spawn_retention as spawn_clip_retention,
+        ClipConfig,
+    },
     data_socket::spawn_data_socket,
-    endpoints::{faces_stream, healthcheck, named_stream},
+    detector::GenericDetector,
+    endpoints::{
+        anonymized_stream, detections_events, faces_stream, frames_with_detections_ws,
+        get_detector_config, get_zones, healthcheck, infer_annotated_image, infer_image, metrics,
+        named_stream, playback, put_detector_config, put_zones, recorded_clips, stream_counts,
+        stream_stats, MAX_UPLOAD_SIZE,
+    },
     inferer::Inferer,
     meter::spawn_meter_logger,
+    nn::{
+        validate_threshold, DetectorConfig, InferModel, ModelOptions, SharedModel, UltrafaceModel,
+        UltrafaceVariant,
+    },
+    recording::{spawn_recorder, spawn_retention as spawn_recording_retention, RecordingConfig},
     router::FrameRouter,
-    INCOMING_FRAMES_CHANNEL, INFER_IMAGES_CHANNEL,
+    scheduler::InferScheduler,
+    tracking::TrackerConfig,
+    webhooks::{read_webhooks_file, spawn_webhooks},
+    zones::read_zones_file,
+    INCOMING_FRAMES_CHANNEL,
 };
 
 #[derive(Debug, FromArgs)]
@@ -25,6 +49,139 @@ struct Args {
     /// address of the data socket
     #[argh(option, default = "String::from(\"127.0.0.1:3001\")")]
     socket_address: String,
+
+    /// ultraface model variant to use, either 320 or 640 (default 320)
+    #[argh(option)]
+    model_variant: Option<UltrafaceVariant>,
+
+    /// maximum IoU of two detections before the less confident one is suppressed (default 0.5
+    /// for Ultraface, the value of the descriptor for --detector)
+    #[argh(option, from_str_fn(parse_max_iou))]
+    max_iou: Option<f32>,
+
+    /// minimum confidence of a detection to be selected (default 0.5 for Ultraface, the value of
+    /// the descriptor for --detector)
+    #[argh(option, from_str_fn(parse_min_confidence))]
+    min_confidence: Option<f32>,
+
+    /// path to a local model file to use instead of the cached or downloaded one
+    #[argh(option)]
+    model_path: Option<PathBuf>,
+
+    /// expected SHA-256 checksum of the model file as hex string
+    #[argh(option)]
+    model_sha256: Option<String>,
+
+    /// never download models, fail if the model file is not available locally
+    #[argh(switch)]
+    offline: bool,
+
+    /// TOML descriptor of a generic ONNX detector to use instead of Ultraface
+    #[argh(option)]
+    detector: Option<PathBuf>,
+
+    /// number of inference workers, each running on its own thread
+    #[argh(option, default = "1")]
+    infer_workers: usize,
+
+    /// maximum number of frames of different streams to infer at once
+    #[argh(option, default = "1")]
+    batch_size: usize,
+
+    /// maximum time in milliseconds to wait for a batch to fill up
+    #[argh(option, default = "5")]
+    batch_wait_ms: u64,
+
+    /// number of matched frames before a track is confirmed and gets an id
+    #[argh(option, default = "3")]
+    track_min_hits: u32,
+
+    /// number of frames without a match after which a track is deleted
+    #[argh(option, default = "5")]
+    track_max_misses: u32,
+
+    /// minimum IoU of a detection with a track to continue it
+    #[argh(option, default = "0.3", from_str_fn(parse_track_min_iou))]
+    track_min_iou: f32,
+
+    /// only serve anonymized streams, no streams showing unanonymized frames
+    #[argh(switch)]
+    disable_raw_streams: bool,
+
+    /// TOML file with the anonymization of streams by name
+    #[argh(option)]
+    anonymization: Option<PathBuf>,
+
+    /// TOML file with the zones of streams by name
+    #[argh(option)]
+    zones: Option<PathBuf>,
+
+    /// TOML file with the webhooks of streams by name
+    #[argh(option)]
+    webhooks: Option<PathBuf>,
+
+    /// directory to record clips of streams to while faces are detected
+    #[argh(option)]
+    clips_dir: Option<PathBuf>,
+
+    /// name of a stream to record clips of, can be given multiple times
+    #[argh(option)]
+    record_clips: Vec<String>,
+
+    /// time in milliseconds recorded before the first detection of a clip
+    #[argh(option, default = "3000")]
+    clip_pre_roll_ms: u64,
+
+    /// time in milliseconds recorded after the last detection of a clip
+    #[argh(option, default = "5000")]
+    clip_post_roll_ms: u64,
+
+    /// maximum age in hours of clips before they are deleted
+    #[argh(option, default = "168")]
+    clip_max_age_hours: u64,
+
+    /// maximum size in MB of all clips before the oldest ones are deleted
+    #[argh(option, default = "1024")]
+    clip_max_size_mb: u64,
+
+    /// directory to continuously record streams to
+    #[argh(option)]
+    recordings_dir: Option<PathBuf>,
+
+    /// name of a stream to record continuously, can be given multiple times
+    #[argh(option)]
+    record: Vec<String>,
+
+    /// duration in seconds of the segment files of recordings
+    #[argh(option, default = "60")]
+    segment_secs: u64,
+
+    /// maximum age in hours of recorded segments before they are deleted
+    #[argh(option, default = "168")]
+    recording_max_age_hours: u64,
+
+    /// maximum size in MB of all recordings before the oldest segments are deleted
+    #[argh(option, default = "10240")]
+    recording_max_size_mb: u64,
+}
+
+fn parse_threshold(name: &str, value: &str) -> Result<f32, String> {
+    let value: f32 = value
+        .parse()
+        .map_err(|_| format!("invalid {name}: {value}"))?;
+    validate_threshold(name, value).map_err(|err| err.to_string())
+}
+
+fn parse_max_iou(value: &str) -> Result<f32, String> {
+    parse_threshold("max_iou", value)
+}
+
+fn parse_min_confidence(value: &str) -> Result<f32, String> {
+    parse_threshold("min_confidence", value)
+}
+
+fn parse_track_min_iou(value: &str) -> Result<f32, String> {
+    parse_threshold("track_min_iou", value)
 }
 
 #[tokio::main]
@@ -36,18 +193,177 @@ async fn main() -> Result<()> {
         .format_timestamp(Some(TimestampPrecision::Millis))
         .init();
 
+    // Load the model once and share it between the inferer and the upload endpoints
+    let batched = args.batch_size > 1;
+    let model: SharedModel = match &args.detector {
+        Some(descriptor_path) => {
+            // The descriptor names its model file and checksum itself
+            if args.model_variant.is_some()
+                || args.model_path.is_some()
+                || args.model_sha256.is_some()
+                || args.offline
+            {
+                bail!(
+                    "--model-variant, --model-path, --model-sha256 and --offline only apply to \
+                     Ultraface and cannot be used with --detector"
+                );
+            }
+
+            log::info!("Loading detector from {}", descriptor_path.display());
+            let detector = GenericDetector::from_descriptor_file(descriptor_path, batched)?;
+            let config = detector.config();
+            detector.set_config(DetectorConfig::new(
+                args.max_iou.unwrap_or(config.max_iou),
+                args.min_confidence.unwrap_or(config.min_confidence),
+            )?)?;
+            Arc::new(detector)
+        }
+        None => {
+            let model_variant = args.model_variant.unwrap_or(UltrafaceVariant::W320H240);
+            let max_iou = args.max_iou.unwrap_or(0.5);
+            let min_confidence = args.min_confidence.unwrap_or(0.5);
+            log::info!(
+                "Loading Ultraface model {model_variant:?} with max_iou {max_iou} and \
+                 min_confidence {min_confidence}"
+            );
+            let model_options = ModelOptions {
+                model_path: args.model_path,
+                sha256: args.model_sha256,
+                offline: args.offline,
+                batched,
+            };
+            Arc::new(
+                UltrafaceModel::with_options(
+                    model_variant,
+                    max_iou,
+                    min_confidence,
+                    &model_options,
+                )
+                .await?,
+            )
+        }
+    };
+
     let (incoming_tx, incoming_rx) = INCOMING_FRAMES_CHANNEL.split();
-    let (infer_tx, infer_rx) = INFER_IMAGES_CHANNEL.split();
-    let frame_router = Arc::new(FrameRouter::new(infer_tx));
+    let scheduler = Arc::new(InferScheduler::new());
+    let tracker_config = TrackerConfig::new(
+        args.track_min_hits,
+        args.track_max_misses,
+        args.track_min_iou,
+    )?;
+    let frame_router = Arc::new(
+        FrameRouter::new(scheduler.clone())
+            .with_tracker_config(tracker_config)
+            .with_raw_streams(!args.disable_raw_streams),
+    );
+
+    if let Some(anonymization_path) = &args.anonymization {
+        log::info!(
+            "Loading anonymization from {}",
+            anonymization_path.display()
+        );
+        for (name, anonymization) in read_anonymization_file(anonymization_path)? {
+            frame_router.set_anonymization(&name, anonymization);
+        }
+    }
+
+    if let Some(zones_path) = &args.zones {
+        log::info!("Loading zones from {}", zones_path.display());
+        for (name, zones) in read_zones_file(zones_path)? {
+            frame_router.set_zones(&name, zones);
+        }
+    }
+
+    if let Some(webhooks_path) = &args.webhooks {
+        log::info!("Loading webhooks from {}", webhooks_path.display());
+        for (name, config) in read_webhooks_file(webhooks_path)? {
+            // Snapshots are sent without anonymization to external receivers
+            if config.snapshot && args.disable_raw_streams {
+                bail!(
+                    "webhook snapshots of stream {name} are not allowed with --disable-raw-streams"
+                );
+            }
+            let rx = frame_router.get_detections_receiver(&name);
+            spawn_webhooks(name, config, rx)?;
+        }
+    }
+
+    let clip_config = match &args.clips_dir {
+        Some(dir) => Some(Arc::new(ClipConfig {
+            dir: dir.clone(),
+            pre_roll_ms: args.clip_pre_roll_ms,
+            post_roll_ms: args.clip_post_roll_ms,
+            max_age_ms: args.clip_max_age_hours * 60 * 60 * 1000,
+            max_bytes: args.clip_max_size_mb * 1024 * 1024,
+        })),
+        None if !args.record_clips.is_empty() => bail!("--record-clips requires --clips-dir"),
+        None => None,
+    };
+    if let Some(clip_config) = &clip_config {
+        let removed = remove_partial_clips(&clip_config.dir)?;
+        if removed > 0 {
+            log::info!("Deleted {removed} unfinished clips");
+        }
+        spawn_clip_retention(ClipConfig::clone(clip_config));
+
+        for name in args.record_clips.iter() {
+            log::info!("Recording clips of {name} to {}", clip_config.dir.display());
+            // Without raw streams, only anonymized frames may be stored
+            let frames_rx = if args.disable_raw_streams {
+                frame_router.get_anonymized_receiver(name)
+            } else {
+                frame_router.get_broadcast_receiver(name)
+            };
+            spawn_clip_recorder(
+                name.clone(),
+                ClipConfig::clone(clip_config),
+                frames_rx,
+                frame_router.get_detections_receiver(name),
+            );
+        }
+    }
+
+    let recording_config = match &args.recordings_dir {
+        Some(dir) => Some(Arc::new(RecordingConfig {
+            dir: dir.clone(),
+            segment_ms: args.segment_secs * 1000,
+            max_age_ms: args.recording_max_age_hours * 60 * 60 * 1000,
+            max_bytes: args.recording_max_size_mb * 1024 * 1024,
+        })),
+        None if !args.record.is_empty() => bail!("--record requires --recordings-dir"),
+        None => None,
+    };
+    if let Some(recording_config) = &recording_config {
+        spawn_recording_retention(RecordingConfig::clone(recording_config));
+        for name in args.record.iter() {
+            log::info!("Recording {name} to {}", recording_config.dir.display());
+            // Without raw streams, only anonymized frames may be stored
+            let frames_rx = if args.disable_raw_streams {
+                frame_router.get_anonymized_receiver(name)
+            } else {
+                frame_router.get_broadcast_receiver(name)
+            };
+            spawn_recorder(
+                name.clone(),
+                RecordingConfig::clone(recording_config),
+                frames_rx,
+            );
+        }
+    }
 
     {
         let frame_router = frame_router.clone();
         tokio::spawn(async move { frame_router.run(incoming_rx).await });
     }
 
-    {
-        tokio::spawn(async move { Inferer::new(infer_rx).await.run().await });
-    }
+    log::info!(
+        "Starting {} inference worker(s) with batch size {}",
+        args.infer_workers,
+        args.batch_size
+    );
+    Inferer::new(scheduler.clone(), model.clone(), args.infer_workers)
+        .with_batching(args.batch_size, Duration::from_millis(args.batch_wait_ms))
+        .spawn()?;
 
     // Create socket to receive image streams via network
     spawn_data_socket(incoming_tx, &args.socket_address).await?;
@@ -59,7 +375,27 @@ async fn main() -> Result<()> {
         .route("/healthcheck", get(healthcheck))
         .route("/stream", get(named_stream))
         .route("/face_stream", get(faces_stream))
-        .layer(Extension(frame_router));
+        .route("/anonymized_stream", get(anonymized_stream))
+        .route("/detections", get(detections_events))
+        .route("/ws", get(frames_with_detections_ws))
+        .route("/streams", get(stream_stats))
+        .route("/zones", get(get_zones).put(put_zones))
+        .route("/counts", get(stream_counts))
+        .route("/metrics", get(metrics))
+        .route("/clips", get(recorded_clips))
+        .route("/playback", get(playback))
+        .route("/infer", post(infer_image))
+        .route("/infer/annotated", post(infer_annotated_image))
+        .route(
+            "/config/detector",
+            get(get_detector_config).put(put_detector_config),
+        )
+        .layer(DefaultBodyLimit::max(MAX_UPLOAD_SIZE))
+        .layer(Extension(frame_router))
+        .layer(Extension(clip_config))
+        .layer(Extension(recording_config))
+        .layer(Extension(scheduler))
+        .layer(Extension(model));
 
     // Serve HTTP server
     let addr: SocketAddr = args.server_address.parse()?;
@@ -0,0 +1,534 @@
+//! Recording of MJPEG clips of streams while objects are detected.
+//!
+//! Clips are stored as concatenated JPEG frames in `<dir>/<stream>/<start_ms>-<end_ms>.mjpeg`,
+//! so that they are indexed by stream and time without a separate database. A clip starts with
+//! the frames of the pre-roll before the first detection and ends after the post-roll without
+//! detections. Clips beyond the retention are deleted periodically.
+use std::{
+    collections::VecDeque,
+    fs::File,
+    io::{BufWriter, Write},
+    path::{Path, PathBuf},
+    time::Duration,
+};
+
+use anyhow::{Context, Result};
+use bytes::Bytes;
+use serde::Serialize;
+use tokio::{sync::broadcast::error::RecvError, task::JoinHandle};
+
+use crate::{
+    detections::FrameDetections, jpeg_from_stream_item, router::timestamp_ms, BroadcastReceiver,
+    DetectionsReceiver,
+};
+
+/// Extension of finished clips.
+const CLIP_EXTENSION: &str = "mjpeg";
+/// Extension of the clip which is currently written.
+const PARTIAL_EXTENSION: &str = "partial";
+/// Interval of deleting clips beyond the retention.
+const RETENTION_INTERVAL: Duration = Duration::from_secs(60);
+
+/// Settings of the clip recording, shared by all recorded streams.
+#[derive(Clone, Debug, PartialEq)]
+pub struct ClipConfig {
+    /// Directory with a subdirectory of clips per stream.
+    pub dir: PathBuf,
+    /// Time in milliseconds recorded before the first detection.
+    pub pre_roll_ms: u64,
+    /// Time in milliseconds recorded after the last detection.
+    pub post_roll_ms: u64,
+    /// Clips older than this are deleted.
+    pub max_age_ms: u64,
+    /// The oldest clips are deleted when all clips together get larger than this.
+    pub max_bytes: u64,
+}
+
+/// Recorded clip of a stream.
+#[derive(Clone, Debug, PartialEq, Serialize)]
+pub struct ClipInfo {
+    /// Name of the directory of the stream.
+    pub stream: String,
+    /// Time of the first frame in milliseconds since the UNIX epoch.
+    pub start_ms: u64,
+    /// Time of the last frame in milliseconds since the UNIX epoch.
+    pub end_ms: u64,
+    pub bytes: u64,
+    #[serde(skip)]
+    pub path: PathBuf,
+}
+
+/// Name of the directory of a stream, replacing characters which are not safe in paths.
+pub fn stream_dir_name(name: &str) -> String {
+    name.chars()
+        .map(|c| {
+            if c.is_ascii_alphanumeric() || c == '-' || c == '_' {
+                c
+            } else {
+                '_'
+            }
+        })
+        .collect()
+}
+
+/// Clip which is currently written.
+struct OpenClip {
+    writer: BufWriter<File>,
+    path: PathBuf,
+    start_ms: u64,
+    last_frame_ms: u64,
+    /// Time of the last detection, the clip ends after the post-roll.
+    last_detection_ms: u64,
+}
+
+/// Recorder of the clips of a single stream.
+pub struct ClipRecorder {
+    config: ClipConfig,
+    dir: PathBuf,
+    /// Recent frames with their timestamps, prepended to the next clip.
+    pre_roll: VecDeque<(u64, Bytes)>,
+    clip: Option<OpenClip>,
+}
+
+impl ClipRecorder {
+    pub fn new(stream: &str, config: ClipConfig) -> Self {
+        Self {
+            dir: config.dir.join(stream_dir_name(stream)),
+            config,
+            pre_roll: VecDeque::new(),
+            clip: None,
+        }
+    }
+
+    /// Record a frame, returning the finished clip if the post-roll is over.
+    pub fn push_frame(&mut self, timestamp_ms: u64, jpeg: Bytes) -> Result<Option<ClipInfo>> {
+        match self.clip.as_mut() {
+            Some(clip) => {
+                clip.writer.write_all(&jpeg)?;
+                clip.last_frame_ms = timestamp_ms;
+
+                if timestamp_ms.saturating_sub(clip.last_detection_ms) > self.config.post_roll_ms {
+                    return self.finish().map(Some);
+                }
+            }
+            None => {
+                self.pre_roll.push_back((timestamp_ms, jpeg));
+                self.prune_pre_roll(timestamp_ms);
+            }
+        }
+
+        Ok(None)
+    }
+
+    /// Start a clip with the pre-roll or extend the current one.
+    pub fn push_detection(&mut self, timestamp_ms: u64) -> Result<()> {
+        if let Some(clip) = self.clip.as_mut() {
+            clip.last_detection_ms = clip.last_detection_ms.max(timestamp_ms);
+            return Ok(());
+        }
+
+        self.prune_pre_roll(timestamp_ms);
+        let start_ms = self
+            .pre_roll
+            .front()
+            .map_or(timestamp_ms, |(frame_ms, _)| *frame_ms);
+        std::fs::create_dir_all(&self.dir)
+            .with_context(|| format!("failed to create {}", self.dir.display()))?;
+        let path = self.dir.join(format!("{start_ms}.{PARTIAL_EXTENSION}"));
+        let mut writer = BufWriter::new(File::create(&path)?);
+
+        let mut last_frame_ms = start_ms;
+        for (frame_ms, jpeg) in self.pre_roll.drain(..) {
+            writer.write_all(&jpeg)?;
+            last_frame_ms = frame_ms;
+        }
+
+        self.clip = Some(OpenClip {
+            writer,
+            path,
+            start_ms,
+            last_frame_ms,
+            last_detection_ms: timestamp_ms,
+        });
+
+        Ok(())
+    }
+
+    /// Drop the frames which are older than the pre-roll before the given time.
+    fn prune_pre_roll(&mut self, timestamp_ms: u64) {
+        let oldest_ms = timestamp_ms.saturating_sub(self.config.pre_roll_ms);
+        while self
+            .pre_roll
+            .front()
+            .is_some_and(|(frame_ms, _)| *frame_ms < oldest_ms)
+        {
+            self.pre_roll.pop_front();
+        }
+    }
+
+    /// Finish the current clip, e.g. when the stream ends.
+    pub fn finish(&mut self) -> Result<ClipInfo> {
+        let clip = self.clip.take().context("no clip is recorded")?;
+        let file = clip.writer.into_inner()?;
+        file.sync_all()?;
+
+        let path = self.dir.join(format!(
+            "{}-{}.{CLIP_EXTENSION}",
+            clip.start_ms, clip.last_frame_ms
+        ));
+        std::fs::rename(&clip.path, &path)?;
+
+        Ok(ClipInfo {
+            stream: stream_dir_name_of(&self.dir),
+            start_ms: clip.start_ms,
+            end_ms: clip.last_frame_ms,
+            bytes: file.metadata()?.len(),
+            path,
+        })
+    }
+
+    pub fn is_recording(&self) -> bool {
+        self.clip.is_some()
+    }
+}
+
+fn stream_dir_name_of(dir: &Path) -> String {
+    dir.file_name()
+        .map(|name| name.to_string_lossy().into_owned())
+        .unwrap_or_default()
+}
+
+/// List the finished clips in `dir`, optionally only of one stream, sorted by start time.
+pub fn list_clips(dir: &Path, stream: Option<&str>) -> Result<Vec<ClipInfo>> {
+    let mut clips = Vec::new();
+    if !dir.exists() {
+        return Ok(clips);
+    }
+
+    for stream_dir in std::fs::read_dir(dir)? {
+        let stream_dir = stream_dir?.path();
+        let stream_name = stream_dir_name_of(&stream_dir);
+        if !stream_dir.is_dir()
+            || stream.is_some_and(|stream| stream_dir_name(stream) != stream_name)
+        {
+            continue;
+        }
+
+        for entry in std::fs::read_dir(&stream_dir)? {
+            let entry = entry?;
+            let path = entry.path();
+            if path.extension().and_then(|ext| ext.to_str()) != Some(CLIP_EXTENSION) {
+                continue;
+            }
+            let Some((start_ms, end_ms)) = path
+                .file_stem()
+                .and_then(|stem| stem.to_str())
+                .and_then(|stem| stem.split_once('-'))
+                .and_then(|(start, end)| Some((start.parse().ok()?, end.parse().ok()?)))
+            else {
+                continue;
+            };
+
+            clips.push(ClipInfo {
+                stream: stream_name.clone(),
+                start_ms,
+                end_ms,
+                bytes: entry.metadata()?.len(),
+                path,
+            });
+        }
+    }
+
+    clips.sort_by_key(|clip| (clip.start_ms, clip.stream.clone()));
+    Ok(clips)
+}
+
+/// Delete clips older than the maximum age and the oldest ones beyond the maximum total size.
+///
+/// Returns the deleted clips.
+pub fn enforce_retention(config: &ClipConfig, now_ms: u64) -> Result<Vec<ClipInfo>> {
+    let clips = list_clips(&config.dir, None)?;
+    let mut total_bytes: u64 = clips.iter().map(|clip| clip.bytes).sum();
+    let oldest_ms = now_ms.saturating_sub(config.max_age_ms);
+
+    let mut deleted = Vec::new();
+    for clip in clips {
+        if clip.end_ms >= oldest_ms && total_bytes <= config.max_bytes {
+            break;
+        }
+        std::fs::remove_file(&clip.path)
+            .with_context(|| format!("failed to delete clip {}", clip.path.display()))?;
+        total_bytes -= clip.bytes;
+        deleted.push(clip);
+    }
+
+    Ok(deleted)
+}
+
+/// Delete the partial clips left behind by an earlier run, returning their number.
+///
+/// Must be called before recording, since it would also delete the clips being recorded.
+pub fn remove_partial_clips(dir: &Path) -> Result<usize> {
+    let mut removed = 0;
+    if !dir.exists() {
+        return Ok(removed);
+    }
+
+    for stream_dir in std::fs::read_dir(dir)? {
+        let stream_dir = stream_dir?.path();
+        if !stream_dir.is_dir() {
+            continue;
+        }
+
+        for entry in std::fs::read_dir(&stream_dir)? {
+            let path = entry?.path();
+            if path.extension().and_then(|ext| ext.to_str()) == Some(PARTIAL_EXTENSION) {
+                std::fs::remove_file(&path)
+                    .with_context(|| format!("failed to delete {}", path.display()))?;
+                removed += 1;
+            }
+        }
+    }
+
+    Ok(removed)
+}
+
+/// Enforce the retention of the clips at startup and then periodically.
+pub fn spawn_retention(config: ClipConfig) -> JoinHandle<()> {
+    crate::spawn_retention("clips", RETENTION_INTERVAL, move || {
+        enforce_retention(&config, timestamp_ms()).map(|deleted| deleted.len())
+    })
+}
+
+/// Frame or detections received by the clip recorder.
+enum Received {
+    Frame(Result<Bytes, RecvError>),
+    Detections(Result<FrameDetections, RecvError>),
+}
+
+/// Record clips of a stream from its frames, triggered by its detections.
+///
+/// The detections receiver keeps the stream infered even if nobody watches it. The recorder
+/// waits for both receivers on a blocking thread, since a detection may open or extend a clip
+/// file at any time.
+pub fn spawn_clip_recorder(
+    name: String,
+    config: ClipConfig,
+    mut frames_rx: BroadcastReceiver,
+    mut detections_rx: DetectionsReceiver,
+) -> JoinHandle<()> {
+    tokio::task::spawn_blocking(move || {
+        let mut recorder = ClipRecorder::new(&name, config);
+
+        loop {
+            let next = futures::executor::block_on(async {
+                tokio::select! {
+                    frame = frames_rx.recv() => Received::Frame(frame),
+                    detections = detections_rx.recv() => Received::Detections(detections),
+                }
+            });
+            let result = match next {
+                Received::Frame(frame) => match frame {
+                    Ok(item) => match jpeg_from_stream_item(&item) {
+                        Some(jpeg) => recorder.push_frame(timestamp_ms(), jpeg),
+                        None => Ok(None),
+                    },
+                    Err(RecvError::Lagged(_)) => Ok(None),
+                    Err(RecvError::Closed) => break,
+                },
+                Received::Detections(detections) => match detections {
+                    Ok(frame) if !frame.detections.is_empty() => {
+                        recorder.push_detection(frame.timestamp_ms).map(|_| None)
+                    }
+                    Ok(_) | Err(RecvError::Lagged(_)) => Ok(None),
+                    Err(RecvError::Closed) => break,
+                },
+            };
+
+            match result {
+                Ok(Some(clip)) => {
+                    log::info!(
+                        "Recorded clip of {name} from {} to {} ({} bytes)",
+                        clip.start_ms,
+                        clip.end_ms,
+                        clip.bytes
+                    );
+                }
+                Ok(None) => (),
+                Err(err) => log::warn!("Failed to record clip of {name}: {err:#}"),
+            }
+        }
+
+        if recorder.is_recording() {
+            if let Err(err) = recorder.finish() {
+                log::warn!("Failed to finish clip of {name}: {err:#}");
+            }
+        }
+    })
+}
+
+#[cfg(test)]
+mod test {
+
+    use super::*;
+    use crate::{as_jpeg_stream_item, broadcast_channel, nn::Detection};
+
+    fn config(dir: &Path) -> ClipConfig {
+        ClipConfig {
+            dir: dir.to_owned(),
+            pre_roll_ms: 200,
+            post_roll_ms: 300,
+            max_age_ms: 10_000,
+            max_bytes: 1024,
+        }
+    }
+
+    fn jpeg(timestamp_ms: u64) -> Bytes {
+        Bytes::from(format!("<{timestamp_ms}>"))
+    }
+
+    #[test]
+    fn test_stream_dir_name() {
+        assert_eq!(stream_dir_name("front-door_1"), "front-door_1");
+        assert_eq!(stream_dir_name("../etc/passwd"), "___etc_passwd");
+    }
+
+    #[test]
+    fn test_clip_with_pre_and_post_roll() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        let mut recorder = ClipRecorder::new("hallway", config(dir.path()));
+
+        // Frames every 100 ms, a face is detected at 500 ms
+        let mut clip = None;
+        for timestamp_ms in (0..=1000).step_by(100) {
+            if timestamp_ms == 500 {
+                recorder.push_detection(timestamp_ms)?;
+            }
+            if let Some(finished) = recorder.push_frame(timestamp_ms, jpeg(timestamp_ms))? {
+                clip = Some(finished);
+                break;
+            }
+        }
+
+        // The clip starts with the pre-roll and ends after the post-roll
+        let clip = clip.unwrap();
+        assert_eq!(
+            (clip.stream.as_str(), clip.start_ms, clip.end_ms),
+            ("hallway", 300, 900)
+        );
+        assert_eq!(
+            std::fs::read_to_string(&clip.path)?,
+            "<300><400><500><600><700><800><900>"
+        );
+        assert_eq!(list_clips(dir.path(), Some("hallway"))?, vec![clip]);
+        assert!(list_clips(dir.path(), Some("lobby"))?.is_empty());
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_detections_extend_clip() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        let mut recorder = ClipRecorder::new("hallway", config(dir.path()));
+
+        recorder.push_detection(0)?;
+        for timestamp_ms in (0..=500).step_by(100) {
+            if timestamp_ms == 200 {
+                recorder.push_detection(timestamp_ms)?;
+            }
+            assert!(recorder
+                .push_frame(timestamp_ms, jpeg(timestamp_ms))?
+                .is_none());
+        }
+
+        // The post-roll ends 300 ms after the second detection
+        let clip = recorder.push_frame(600, jpeg(600))?.unwrap();
+        assert_eq!((clip.start_ms, clip.end_ms), (0, 600));
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_spawn_clip_recorder() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        let (frames_tx, frames_rx) = broadcast_channel();
+        let (detections_tx, detections_rx) = broadcast_channel();
+        let handle = spawn_clip_recorder(
+            "hallway".to_owned(),
+            config(dir.path()),
+            frames_rx,
+            detections_rx,
+        );
+
+        let faces = vec![Detection::new(0, "face", 0.9, [0.25, 0.25, 0.5, 0.5])];
+        let detections = FrameDetections::new(0, timestamp_ms(), 32, 24, &faces, Bytes::new());
+        detections_tx.send(detections)?;
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        frames_tx.send(as_jpeg_stream_item(b"<frame>"))?;
+        tokio::time::sleep(Duration::from_millis(50)).await;
+
+        // The clip is finished once the stream ends
+        drop((frames_tx, detections_tx));
+        handle.await?;
+        let clips = list_clips(dir.path(), Some("hallway"))?;
+        assert_eq!(clips.len(), 1);
+        assert_eq!(std::fs::read(&clips[0].path)?, b"<frame>");
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_remove_partial_clips() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        let mut recorder = ClipRecorder::new("hallway", config(dir.path()));
+        recorder.push_detection(0)?;
+        recorder.push_frame(0, jpeg(0))?;
+        drop(recorder);
+
+        // The clip which was never finished is deleted, finished clips are kept
+        let mut recorder = ClipRecorder::new("lobby", config(dir.path()));
+        recorder.push_detection(0)?;
+        recorder.finish()?;
+
+        assert_eq!(remove_partial_clips(dir.path())?, 1);
+        assert!(!dir.path().join("hallway").join("0.partial").exists());
+        assert_eq!(list_clips(dir.path(), None)?.len(), 1);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_retention() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        let config = ClipConfig {
+            max_age_ms: 5000,
+            max_bytes: 20,
+            ..config(dir.path())
+        };
+
+        for (stream, start_ms) in [("a", 1000), ("b", 7000), ("a", 8000), ("b", 9000)] {
+            let mut recorder = ClipRecorder::new(stream, config.clone());
+            recorder.push_frame(start_ms, Bytes::from_static(&[0; 8]))?;
+            recorder.push_detection(start_ms)?;
+            recorder.finish()?;
+        }
+
+        // The clip of `a` at 1000 is too old, the one of `b` at 7000 exceeds the size
+        let deleted: Vec<_> = enforce_retention(&config, 10_000)?
+            .into_iter()
+            .map(|clip| (clip.stream, clip.start_ms))
+            .collect();
+        assert_eq!(
+            deleted,
+            vec![("a".to_owned(), 1000), ("b".to_owned(), 7000)]
+        );
+
+        let kept: Vec<_> = list_clips(dir.path(), None)?
+            .into_iter()
+            .map(|clip| clip.start_ms)
+            .collect();
+        assert_eq!(kept, vec![8000, 9000]);
+
+        Ok(())
+    }
+}
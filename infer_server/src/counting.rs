@@ -0,0 +1,409 @@
+//! Counting of tracked objects entering and leaving zones and crossing lines.
+//!
+use std::{
+    collections::{BTreeMap, HashMap, HashSet},
+    fmt::Write,
+    sync::{Arc, Mutex},
+};
+
+use serde::Serialize;
+
+use crate::{
+    nn::{Detection, Point},
+    zones::StreamZones,
+};
+
+/// Counts of a zone.
+#[derive(Clone, Debug, Default, PartialEq, Serialize)]
+pub struct ZoneCounts {
+    pub name: String,
+    /// Objects which entered the zone, including the ones appearing inside.
+    pub entered: u64,
+    /// Objects which left the zone, including the ones disappearing inside.
+    pub left: u64,
+    /// Objects currently inside the zone.
+    pub occupancy: u64,
+}
+
+/// Counts of a line.
+#[derive(Clone, Debug, Default, PartialEq, Serialize)]
+pub struct LineCounts {
+    pub name: String,
+    /// Crossings from the left to the right side when looking from the start to the end.
+    pub forward: u64,
+    /// Crossings from the right to the left side.
+    pub backward: u64,
+}
+
+/// Counts of all zones and lines of a stream.
+#[derive(Clone, Debug, Default, PartialEq, Serialize)]
+pub struct StreamCounts {
+    pub name: String,
+    pub zones: Vec<ZoneCounts>,
+    pub lines: Vec<LineCounts>,
+}
+
+/// Counter shared by the inference workers of a stream.
+pub type SharedCounter = Arc<Mutex<StreamCounter>>;
+
+/// Last known position of a tracked object.
+#[derive(Debug)]
+struct TrackState {
+    center: Point,
+    zone: Option<String>,
+}
+
+/// Counter of the tracked objects of a stream.
+#[derive(Debug, Default)]
+pub struct StreamCounter {
+    name: String,
+    zones: BTreeMap<String, ZoneCounts>,
+    lines: BTreeMap<String, LineCounts>,
+    tracks: HashMap<u64, TrackState>,
+}
+
+impl StreamCounter {
+    pub fn new(name: &str) -> Self {
+        Self {
+            name: name.to_owned(),
+            ..Default::default()
+        }
+    }
+
+    /// Count the tracked detections of a frame.
+    ///
+    /// `live_tracks` are the ids of all tracks which still exist, also the ones missed in this
+    /// frame. Objects of tracks which ended leave their zone.
+    pub fn update(
+        &mut self,
+        zones: &StreamZones,
+        detections: &[Detection],
+        live_tracks: &HashSet<u64>,
+    ) {
+        for zone in zones.zones.iter() {
+            self.zone_counts(&zone.name);
+        }
+        for line in zones.lines.iter() {
+            self.line_counts(&line.name);
+        }
+
+        for detection in detections.iter() {
+            let Some(track_id) = detection.track_id else {
+                continue;
+            };
+            let bbox = detection.bbox;
+            let center = [(bbox[0] + bbox[2]) / 2.0, (bbox[1] + bbox[3]) / 2.0];
+
+            let previous = self.tracks.remove(&track_id);
+            let previous_zone = previous.as_ref().and_then(|state| state.zone.clone());
+            if previous_zone != detection.zone {
+                if let Some(zone) = previous_zone {
+                    self.zone_counts(&zone).left += 1;
+                }
+                if let Some(zone) = detection.zone.as_ref() {
+                    self.zone_counts(zone).entered += 1;
+                }
+            }
+
+            if let Some(previous) = previous {
+                for line in zones.lines.iter() {
+                    match line.crossing(previous.center, center) {
+                        Some(true) => self.line_counts(&line.name).forward += 1,
+                        Some(false) => self.line_counts(&line.name).backward += 1,
+                        None => (),
+                    }
+                }
+            }
+
+            self.tracks.insert(
+                track_id,
+                TrackState {
+                    center,
+                    zone: detection.zone.clone(),
+                },
+            );
+        }
+
+        let ended: Vec<_> = self
+            .tracks
+            .keys()
+            .filter(|track_id| !live_tracks.contains(track_id))
+            .copied()
+            .collect();
+        for track_id in ended {
+            if let Some(zone) = self.tracks.remove(&track_id).and_then(|state| state.zone) {
+                self.zone_counts(&zone).left += 1;
+            }
+        }
+
+        for counts in self.zones.values_mut() {
+            counts.occupancy = 0;
+        }
+        for zone in self.tracks.values().filter_map(|state| state.zone.as_ref()) {
+            if let Some(counts) = self.zones.get_mut(zone) {
+                counts.occupancy += 1;
+            }
+        }
+    }
+
+    /// Forget all tracked objects, counting the ones in a zone as leaving it.
+    ///
+    /// Has to be called whenever the tracker of the stream is recreated or its zones change, since
+    /// track ids are reused by a new tracker and the zones of known tracks may be gone.
+    pub fn reset_tracks(&mut self) {
+        for (_track_id, state) in self.tracks.drain() {
+            if let Some(zone) = state.zone {
+                if let Some(counts) = self.zones.get_mut(&zone) {
+                    counts.left += 1;
+                }
+            }
+        }
+        for counts in self.zones.values_mut() {
+            counts.occupancy = 0;
+        }
+    }
+
+    pub fn counts(&self) -> StreamCounts {
+        StreamCounts {
+            name: self.name.clone(),
+            zones: self.zones.values().cloned().collect(),
+            lines: self.lines.values().cloned().collect(),
+        }
+    }
+
+    fn zone_counts(&mut self, name: &str) -> &mut ZoneCounts {
+        self.zones
+            .entry(name.to_owned())
+            .or_insert_with(|| ZoneCounts {
+                name: name.to_owned(),
+                ..Default::default()
+            })
+    }
+
+    fn line_counts(&mut self, name: &str) -> &mut LineCounts {
+        self.lines
+            .entry(name.to_owned())
+            .or_insert_with(|| LineCounts {
+                name: name.to_owned(),
+                ..Default::default()
+            })
+    }
+}
+
+/// Render the counts of all streams in the Prometheus text format.
+pub fn render_metrics(counts: &[StreamCounts]) -> String {
+    let mut metrics = String::new();
+
+    write_zone_metric(
+        &mut metrics,
+        counts,
+        ("infer_zone_entered_total", "counter"),
+        "Objects which entered a zone.",
+        |zone| zone.entered,
+    );
+    write_zone_metric(
+        &mut metrics,
+        counts,
+        ("infer_zone_left_total", "counter"),
+        "Objects which left a zone.",
+        |zone| zone.left,
+    );
+    write_zone_metric(
+        &mut metrics,
+        counts,
+        ("infer_zone_occupancy", "gauge"),
+        "Objects currently inside a zone.",
+        |zone| zone.occupancy,
+    );
+
+    let metric = "infer_line_crossings_total";
+    writeln!(
+        metrics,
+        "# HELP {metric} Objects which crossed a line.\n# TYPE {metric} counter"
+    )
+    .ok();
+    for stream in counts.iter() {
+        for line in stream.lines.iter() {
+            for (direction, value) in [("forward", line.forward), ("backward", line.backward)] {
+                writeln!(
+                    metrics,
+                    "{metric}{{stream=\"{}\",line=\"{}\",direction=\"{direction}\"}} {value}",
+                    escape_label(&stream.name),
+                    escape_label(&line.name),
+                )
+                .ok();
+            }
+        }
+    }
+
+    metrics
+}
+
+/// Write a metric with a sample for every zone of every stream.
+fn write_zone_metric(
+    metrics: &mut String,
+    counts: &[StreamCounts],
+    (metric, kind): (&str, &str),
+    help: &str,
+    value: impl Fn(&ZoneCounts) -> u64,
+) {
+    writeln!(metrics, "# HELP {metric} {help}\n# TYPE {metric} {kind}").ok();
+    for stream in counts.iter() {
+        for zone in stream.zones.iter() {
+            writeln!(
+                metrics,
+                "{metric}{{stream=\"{}\",zone=\"{}\"}} {}",
+                escape_label(&stream.name),
+                escape_label(&zone.name),
+                value(zone)
+            )
+            .ok();
+        }
+    }
+}
+
+fn escape_label(value: &str) -> String {
+    value
+        .replace('\\', "\\\\")
+        .replace('"', "\\\"")
+        .replace('\n', "\\n")
+}
+
+#[cfg(test)]
+mod test {
+
+    use super::*;
+    use crate::zones::{CountingLine, Zone};
+
+    fn stream_zones() -> StreamZones {
+        StreamZones {
+            zones: vec![Zone {
+                name: "door".to_owned(),
+                polygon: vec![[0.0, 0.0], [0.5, 0.0], [0.5, 1.0], [0.0, 1.0]],
+            }],
+            lines: vec![CountingLine {
+                name: "entrance".to_owned(),
+                start: [0.5, 0.0],
+                end: [0.5, 1.0],
+            }],
+            ..Default::default()
+        }
+    }
+
+    /// Tracked detection centered at `x`, labeled with its zone.
+    fn tracked(zones: &StreamZones, track_id: u64, x: f32) -> Detection {
+        let mut detections = vec![Detection::new(0, "face", 0.9, [x - 0.1, 0.4, x + 0.1, 0.6])];
+        zones.apply(&mut detections);
+        detections[0].track_id = Some(track_id);
+        detections.remove(0)
+    }
+
+    #[test]
+    fn test_enter_cross_and_leave() {
+        let zones = stream_zones();
+        let mut counter = StreamCounter::new("hallway");
+        let live = HashSet::from([1, 2]);
+
+        // Two people appear outside the zone, one walks into it crossing the line
+        counter.update(
+            &zones,
+            &[tracked(&zones, 1, 0.75), tracked(&zones, 2, 0.875)],
+            &live,
+        );
+        counter.update(
+            &zones,
+            &[tracked(&zones, 1, 0.25), tracked(&zones, 2, 0.875)],
+            &live,
+        );
+
+        let counts = counter.counts();
+        assert_eq!(
+            counts.zones,
+            vec![ZoneCounts {
+                name: "door".to_owned(),
+                entered: 1,
+                left: 0,
+                occupancy: 1,
+            }]
+        );
+        assert_eq!(
+            counts.lines,
+            vec![LineCounts {
+                name: "entrance".to_owned(),
+                forward: 1,
+                backward: 0,
+            }]
+        );
+
+        // A missed track is still inside, an ended one has left
+        counter.update(&zones, &[], &live);
+        assert_eq!(counter.counts().zones[0].occupancy, 1);
+
+        counter.update(&zones, &[], &HashSet::from([2]));
+        let counts = counter.counts();
+        assert_eq!((counts.zones[0].left, counts.zones[0].occupancy), (1, 0));
+    }
+
+    #[test]
+    fn test_reset_tracks() {
+        let zones = stream_zones();
+        let mut counter = StreamCounter::new("hallway");
+        let live = HashSet::from([1]);
+
+        counter.update(&zones, &[tracked(&zones, 1, 0.25)], &live);
+        counter.reset_tracks();
+        let counts = counter.counts();
+        assert_eq!((counts.zones[0].left, counts.zones[0].occupancy), (1, 0));
+
+        // A reused track id is a new object and crosses no line from its old position
+        counter.update(&zones, &[tracked(&zones, 1, 0.75)], &live);
+        let counts = counter.counts();
+        assert_eq!((counts.zones[0].entered, counts.zones[0].left), (1, 1));
+        assert_eq!(counts.lines[0].forward + counts.lines[0].backward, 0);
+    }
+
+    #[test]
+    fn test_untracked_detections_are_not_counted() {
+        let zones = stream_zones();
+        let mut counter = StreamCounter::new("hallway");
+
+        let mut detection = tracked(&zones, 1, 0.25);
+        detection.track_id = None;
+        counter.update(&zones, &[detection], &HashSet::new());
+
+        assert_eq!(
+            counter.counts().zones[0],
+            ZoneCounts {
+                name: "door".to_owned(),
+                ..Default::default()
+            }
+        );
+    }
+
+    #[test]
+    fn test_render_metrics() {
+        let counts = StreamCounts {
+            name: "hall\"way".to_owned(),
+            zones: vec![ZoneCounts {
+                name: "door".to_owned(),
+                entered: 3,
+                left: 2,
+                occupancy: 1,
+            }],
+            lines: vec![LineCounts {
+                name: "entrance".to_owned(),
+                forward: 4,
+                backward: 5,
+            }],
+        };
+
+        let metrics = render_metrics(&[counts]);
+        assert!(metrics.contains("# TYPE infer_zone_occupancy gauge\n"));
+        assert!(
+            metrics.contains("infer_zone_entered_total{stream=\"hall\\\"way\",zone=\"door\"} 3\n")
+        );
+        assert!(metrics.contains(
+            "infer_line_crossings_total{stream=\"hall\\\"way\",line=\"entrance\",direction=\"backward\"} 5\n"
+        ));
+    }
+}
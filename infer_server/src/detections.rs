@@ -0,0 +1,135 @@
+//! Detection results shared by the inferer and the endpoints.
+//!
+use bytes::Bytes;
+use serde::Serialize;
+
+use crate::nn::Detection;
+
+/// Detection with relative and pixel coordinates.
+#[derive(Clone, Debug, PartialEq, Serialize)]
+pub struct DetectionOutput {
+    /// Class, score and relative coordinates of the detection.
+    #[serde(flatten)]
+    pub detection: Detection,
+    /// Pixel coordinates `[x_top_left, y_top_left, x_bottom_right, y_bottom_right]`.
+    pub bbox_px: [u32; 4],
+    /// Pixel coordinates `[x, y]` of the landmarks.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub landmarks_px: Option<Vec<[u32; 2]>>,
+}
+
+impl DetectionOutput {
+    pub fn new(detection: Detection, width: u32, height: u32) -> Self {
+        let bbox = detection.bbox;
+        let to_px = |rel: f32, max: u32| (rel * max as f32).round().clamp(0.0, max as f32) as u32;
+        let bbox_px = [
+            to_px(bbox[0], width),
+            to_px(bbox[1], height),
+            to_px(bbox[2], width),
+            to_px(bbox[3], height),
+        ];
+
+        let landmarks_px = detection.landmarks.as_ref().map(|landmarks| {
+            landmarks
+                .iter()
+                .map(|point| [to_px(point[0], width), to_px(point[1], height)])
+                .collect()
+        });
+
+        Self {
+            detection,
+            bbox_px,
+            landmarks_px,
+        }
+    }
+}
+
+/// Detections of a single frame of a stream.
+#[derive(Clone, Debug, Serialize)]
+pub struct FrameDetections {
+    /// Sequence number of the frame in its stream.
+    pub seq: u64,
+    /// Time of receiving the frame in milliseconds since the UNIX epoch.
+    pub timestamp_ms: u64,
+    pub width: u32,
+    pub height: u32,
+    pub detections: Vec<DetectionOutput>,
+    /// Whether the detections were infered from this frame or reused from an earlier one.
+    pub fresh: bool,
+    /// JPEG data of the infered frame.
+    #[serde(skip)]
+    pub jpeg: Bytes,
+}
+
+impl FrameDetections {
+    pub fn new(
+        seq: u64,
+        timestamp_ms: u64,
+        width: u32,
+        height: u32,
+        detections: &[Detection],
+        jpeg: Bytes,
+    ) -> Self {
+        let detections = detections
+            .iter()
+            .map(|detection| DetectionOutput::new(detection.clone(), width, height))
+            .collect();
+
+        Self {
+            seq,
+            timestamp_ms,
+            width,
+            height,
+            detections,
+            fresh: true,
+            jpeg,
+        }
+    }
+
+    /// Mark the detections as reused from an earlier frame.
+    pub fn reused(mut self) -> Self {
+        self.fresh = false;
+        self
+    }
+}
+
+#[cfg(test)]
+mod test {
+
+    use super::*;
+
+    #[test]
+    fn test_detection_pixel_coordinates() {
+        let detection = Detection::new(0, "face", 0.9, [0.25, 0.5, 0.75, 1.1]);
+        let output = DetectionOutput::new(detection, 640, 480);
+        assert_eq!(output.bbox_px, [160, 240, 480, 480]);
+    }
+
+    #[test]
+    fn test_detection_output_json() {
+        let detection = Detection::new(1, "person", 0.5, [0.0, 0.0, 0.5, 0.5]);
+        let output = DetectionOutput::new(detection, 100, 100);
+        assert_eq!(
+            serde_json::to_value(&output).unwrap(),
+            serde_json::json!({
+                "class_id": 1,
+                "label": "person",
+                "score": 0.5,
+                "bbox": [0.0, 0.0, 0.5, 0.5],
+                "bbox_px": [0, 0, 50, 50],
+            })
+        );
+
+        let detection = Detection::new(0, "face", 0.5, [0.0, 0.0, 0.5, 0.5])
+            .with_landmarks(Some(vec![[0.125, 0.25], [0.375, 0.25]]));
+        let output = serde_json::to_value(DetectionOutput::new(detection, 100, 100)).unwrap();
+        assert_eq!(
+            output["landmarks"],
+            serde_json::json!([[0.125, 0.25], [0.375, 0.25]])
+        );
+        assert_eq!(
+            output["landmarks_px"],
+            serde_json::json!([[13, 25], [38, 25]])
+        );
+    }
+}
@@ -0,0 +1,732 @@
+//! Generic ONNX detector configured by a TOML descriptor.
+//!
+//! A descriptor defines where to find the model, how to pre-process images for it and how to
+//! decode its output. For example, a YOLO-style person detector can be described like this:
+//!
+//! ```toml
+//! model_path = "yolov5n.onnx"
+//! width = 640
+//! height = 640
+//! mean = [0.0, 0.0, 0.0]
+//! std = [1.0, 1.0, 1.0]
+//!
+//! labels = ["person"]
+//!
+//! [output]
+//! kind = "yolo"
+//! ```
+use std::{
+    path::{Path, PathBuf},
+    sync::RwLock,
+};
+
+use anyhow::{bail, Context, Result};
+use image::RgbImage;
+use ndarray::{ArrayViewD, Axis, Ix3};
+use serde::Deserialize;
+use tract_onnx::prelude::*;
+
+use crate::nn::{
+    batch_item, images_to_tensor, load_model, non_maximum_suppression, verify_model_file, Bbox,
+    ChannelOrder, Detection, DetectorConfig, InferModel, NnModel, NnOut, Point,
+};
+
+/// Description of a generic ONNX detector.
+#[derive(Clone, Debug, Deserialize, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct DetectorDescriptor {
+    /// Path to the ONNX model file, relative paths are resolved from the descriptor's directory.
+    pub model_path: PathBuf,
+    /// Expected SHA-256 checksum of the model file as hex string.
+    #[serde(default)]
+    pub sha256: Option<String>,
+    /// Width of the model input.
+    pub width: u32,
+    /// Height of the model input.
+    pub height: u32,
+    /// Order of the color channels in the model input.
+    #[serde(default)]
+    pub channel_order: ChannelOrder,
+    /// Per-channel mean in RGB order, subtracted from pixel values scaled to `[0, 1]`.
+    #[serde(default = "default_mean")]
+    pub mean: [f32; 3],
+    /// Per-channel standard deviation in RGB order, dividing the pixel values after the mean.
+    #[serde(default = "default_std")]
+    pub std: [f32; 3],
+    #[serde(default = "default_threshold")]
+    pub max_iou: f32,
+    #[serde(default = "default_threshold")]
+    pub min_confidence: f32,
+    /// Labels of the classes by class index, classes without label are named by their index.
+    ///
+    /// Class indices start at the first class column, so score columns skipped with
+    /// `first_class_column` (e.g. a background class) have no label.
+    #[serde(default)]
+    pub labels: Vec<String>,
+    /// Decoding scheme of the model output.
+    pub output: OutputDecoding,
+}
+
+fn default_mean() -> [f32; 3] {
+    [0.0; 3]
+}
+
+fn default_std() -> [f32; 3] {
+    [1.0; 3]
+}
+
+fn default_threshold() -> f32 {
+    0.5
+}
+
+/// Supported schemes to decode the output of a detector.
+#[derive(Clone, Debug, Deserialize, PartialEq)]
+#[serde(tag = "kind", rename_all = "snake_case", deny_unknown_fields)]
+pub enum OutputDecoding {
+    /// Separate score and box tensors like the output of Ultraface.
+    ///
+    /// The scores output is a `1xKxC` tensor of class scores per candidate, of which the columns
+    /// before `first_class_column` (e.g. a background class) are ignored. Class indices count
+    /// from `first_class_column`. The boxes output is a
+    /// `1xKx4` tensor of **relative** coordinates
+    /// `[x_top_left, y_top_left, x_bottom_right, y_bottom_right]`. The optional landmarks output
+    /// is a `1xKx2N` tensor of N **relative** `[x, y]` landmarks per candidate, e.g. the five
+    /// facial landmarks of RetinaFace.
+    BoxesScores {
+        #[serde(default)]
+        scores_output: usize,
+        #[serde(default = "default_boxes_output")]
+        boxes_output: usize,
+        #[serde(default = "default_first_class_column")]
+        first_class_column: usize,
+        #[serde(default)]
+        landmarks_output: Option<usize>,
+    },
+    /// Single YOLO-style output with objectness and class scores.
+    ///
+    /// The output is a `1xKx(5+2N+C)` tensor with rows
+    /// `[cx, cy, w, h, objectness, landmarks.., class scores..]` and N `[x, y]` landmarks like in
+    /// YOLOv5-face. The box center and size as well as the landmarks are given in pixels of the
+    /// model input. The score of a candidate is its objectness times its best class score, or
+    /// only its objectness without classes.
+    Yolo {
+        #[serde(default)]
+        output: usize,
+        #[serde(default)]
+        landmarks: usize,
+    },
+}
+
+fn default_boxes_output() -> usize {
+    1
+}
+
+fn default_first_class_column() -> usize {
+    1
+}
+
+impl DetectorDescriptor {
+    /// Read a descriptor from a TOML file.
+    pub fn from_file(path: impl AsRef<Path>) -> Result<Self> {
+        let path = path.as_ref();
+        let content = std::fs::read_to_string(path)
+            .with_context(|| format!("failed to read descriptor {}", path.display()))?;
+        let mut descriptor = Self::from_toml(&content)
+            .with_context(|| format!("invalid descriptor {}", path.display()))?;
+
+        if descriptor.model_path.is_relative() {
+            if let Some(dir) = path.parent() {
+                descriptor.model_path = dir.join(&descriptor.model_path);
+            }
+        }
+
+        Ok(descriptor)
+    }
+
+    /// Parse and validate a descriptor from a TOML string.
+    pub fn from_toml(content: &str) -> Result<Self> {
+        let descriptor: Self = toml::from_str(content)?;
+        descriptor.validate()?;
+
+        Ok(descriptor)
+    }
+
+    fn validate(&self) -> Result<()> {
+        if self.width == 0 || self.height == 0 {
+            bail!(
+                "input size has to be positive, got {}x{}",
+                self.width,
+                self.height
+            );
+        }
+        if self.std.contains(&0.0) {
+            bail!("std must not contain zeros, got {:?}", self.std);
+        }
+        DetectorConfig::new(self.max_iou, self.min_confidence)?;
+
+        Ok(())
+    }
+
+    /// Get the label of a class.
+    fn label(&self, class_id: usize) -> String {
+        self.labels
+            .get(class_id)
+            .cloned()
+            .unwrap_or_else(|| format!("class_{class_id}"))
+    }
+}
+
+/// Candidate bounding box with its score, class index and landmarks.
+type Candidate = (Bbox, f32, usize, Option<Vec<Point>>);
+
+/// Loaded generic detector, ready for inference with post-processing thresholds.
+pub struct GenericDetector {
+    model: NnModel,
+    descriptor: DetectorDescriptor,
+    batched: bool,
+    config: RwLock<DetectorConfig>,
+}
+
+impl GenericDetector {
+    /// Load the detector described by a TOML descriptor file.
+    ///
+    /// A `batched` detector accepts batches of several frames at once.
+    pub fn from_descriptor_file(path: impl AsRef<Path>, batched: bool) -> Result<Self> {
+        Self::new(DetectorDescriptor::from_file(path)?, batched)
+    }
+
+    /// Load and prepare the model of a descriptor for inference.
+    pub fn new(descriptor: DetectorDescriptor, batched: bool) -> Result<Self> {
+        descriptor.validate()?;
+
+        let model_file_path = &descriptor.model_path;
+        if !model_file_path.is_file() {
+            bail!("model file {} not found", model_file_path.display());
+        }
+        verify_model_file(model_file_path, descriptor.sha256.as_deref(), false)?;
+
+        let model = load_model(
+            model_file_path,
+            descriptor.width,
+            descriptor.height,
+            batched,
+        )?;
+        let config = DetectorConfig::new(descriptor.max_iou, descriptor.min_confidence)?;
+        log::info!("Initialized detector {}", model_file_path.display());
+
+        Ok(Self {
+            model,
+            descriptor,
+            batched,
+            config: RwLock::new(config),
+        })
+    }
+
+    /// Pre-process a batch of images to be used as inference input.
+    fn preproc(&self, inputs: &[RgbImage]) -> Result<TValue> {
+        let tensor = images_to_tensor(
+            inputs,
+            self.descriptor.width,
+            self.descriptor.height,
+            self.descriptor.mean,
+            self.descriptor.std,
+            self.descriptor.channel_order,
+        )?;
+
+        Ok(TValue::from_const(Arc::new(tensor)))
+    }
+
+    /// Post-process raw inference output of the batch item `index` to selected detections.
+    fn postproc(&self, raw_nn_out: &NnOut, index: usize) -> Result<Vec<Detection>> {
+        let config = self.config();
+
+        let output = |output: usize| batch_item(raw_nn_out, output, index);
+
+        let mut candidates = match self.descriptor.output {
+            OutputDecoding::BoxesScores {
+                scores_output,
+                boxes_output,
+                first_class_column,
+                landmarks_output,
+            } => decode_boxes_scores(
+                output(scores_output)?,
+                output(boxes_output)?,
+                landmarks_output.map(output).transpose()?,
+                first_class_column,
+                config.min_confidence,
+            )?,
+            OutputDecoding::Yolo {
+                output: index,
+                landmarks,
+            } => decode_yolo(
+                output(index)?,
+                landmarks,
+                self.descriptor.width,
+                self.descriptor.height,
+                config.min_confidence,
+            )?,
+        };
+
+        // Sort by **ascending** scores as required by the non-maximum suppression
+        candidates.sort_by(|a, b| a.1.total_cmp(&b.1));
+        let candidates = candidates
+            .into_iter()
+            .map(|(bbox, score, class_id, landmarks)| {
+                Detection::new(class_id, self.descriptor.label(class_id), score, bbox)
+                    .with_landmarks(landmarks)
+            })
+            .collect();
+
+        Ok(non_maximum_suppression(candidates, config.max_iou))
+    }
+}
+
+impl InferModel for GenericDetector {
+    fn run(&self, input: &RgbImage) -> Result<Vec<Detection>> {
+        let valid_input = tvec!(self.preproc(std::slice::from_ref(input))?);
+        let raw_nn_out = self.model.run(valid_input)?;
+        let selected_bboxes = self.postproc(&raw_nn_out, 0)?;
+
+        Ok(selected_bboxes)
+    }
+
+    fn run_batch(&self, inputs: &[RgbImage]) -> Result<Vec<Vec<Detection>>> {
+        if !self.batched || inputs.len() < 2 {
+            return inputs.iter().map(|input| self.run(input)).collect();
+        }
+
+        let valid_input = tvec!(self.preproc(inputs)?);
+        let raw_nn_out = self.model.run(valid_input)?;
+
+        (0..inputs.len())
+            .map(|index| self.postproc(&raw_nn_out, index))
+            .collect()
+    }
+
+    fn config(&self) -> DetectorConfig {
+        *self.config.read().unwrap()
+    }
+
+    fn set_config(&self, config: DetectorConfig) -> Result<()> {
+        let config = config.validate()?;
+        *self.config.write().unwrap() = config;
+
+        Ok(())
+    }
+}
+
+/// Get the index and value of the highest score.
+fn best_class(scores: impl Iterator<Item = f32>) -> Option<(usize, f32)> {
+    scores.enumerate().max_by(|(_, a), (_, b)| a.total_cmp(b))
+}
+
+/// Decode separate `1xKxC` score, `1xKx4` box and optional `1xKx2N` landmark tensors to
+/// candidates above `min_confidence`.
+fn decode_boxes_scores(
+    scores: ArrayViewD<f32>,
+    boxes: ArrayViewD<f32>,
+    landmarks: Option<ArrayViewD<f32>>,
+    first_class_column: usize,
+    min_confidence: f32,
+) -> Result<Vec<Candidate>> {
+    let scores = scores
+        .into_dimensionality::<Ix3>()
+        .context("scores output has to be a 1xKxC tensor")?;
+    let boxes = boxes
+        .into_dimensionality::<Ix3>()
+        .context("boxes output has to be a 1xKx4 tensor")?;
+    if boxes.shape()[2] != 4 || boxes.shape()[1] != scores.shape()[1] {
+        bail!(
+            "shapes of scores {:?} and boxes {:?} do not match",
+            scores.shape(),
+            boxes.shape()
+        );
+    }
+    let landmarks = landmarks
+        .map(|landmarks| {
+            let landmarks = landmarks
+                .into_dimensionality::<Ix3>()
+                .context("landmarks output has to be a 1xKx2N tensor")?;
+            if landmarks.shape()[1] != scores.shape()[1] || landmarks.shape()[2] % 2 != 0 {
+                bail!(
+                    "shapes of scores {:?} and landmarks {:?} do not match",
+                    scores.shape(),
+                    landmarks.shape()
+                );
+            }
+            Ok(landmarks.index_axis_move(Axis(0), 0))
+        })
+        .transpose()?;
+
+    let candidates = scores
+        .index_axis(Axis(0), 0)
+        .outer_iter()
+        .zip(boxes.index_axis(Axis(0), 0).outer_iter())
+        .enumerate()
+        .filter_map(|(index, (scores, bbox))| {
+            let (class, score) = best_class(scores.iter().skip(first_class_column).copied())?;
+            if score <= min_confidence {
+                return None;
+            }
+
+            let points = landmarks.as_ref().map(|landmarks| {
+                to_points(
+                    landmarks.index_axis(Axis(0), index).iter().copied(),
+                    1.0,
+                    1.0,
+                )
+            });
+
+            Some(([bbox[0], bbox[1], bbox[2], bbox[3]], score, class, points))
+        })
+        .collect();
+
+    Ok(candidates)
+}
+
+/// Group flat `[x, y, x, y, ..]` coordinates to points, dividing them by `width` and `height`.
+fn to_points(coordinates: impl Iterator<Item = f32>, width: f32, height: f32) -> Vec<Point> {
+    let coordinates: Vec<f32> = coordinates.collect();
+    coordinates
+        .chunks_exact(2)
+        .map(|point| [point[0] / width, point[1] / height])
+        .collect()
+}
+
+/// Decode a YOLO-style `1xKx(5+2N+C)` tensor to candidates above `min_confidence`.
+///
+/// The boxes are converted from pixel centers and sizes to relative corner coordinates, the N
+/// landmarks from pixel to relative coordinates.
+fn decode_yolo(
+    output: ArrayViewD<f32>,
+    landmarks: usize,
+    width: u32,
+    height: u32,
+    min_confidence: f32,
+) -> Result<Vec<Candidate>> {
+    let output = output
+        .into_dimensionality::<Ix3>()
+        .context("YOLO output has to be a 1xKx(5+2N+C) tensor")?;
+    let first_class_column = 5 + 2 * landmarks;
+    if output.shape()[2] < first_class_column {
+        bail!(
+            "YOLO output rows need at least {first_class_column} values, got {:?}",
+            output.shape()
+        );
+    }
+
+    let (width, height) = (width as f32, height as f32);
+    let candidates = output
+        .index_axis(Axis(0), 0)
+        .outer_iter()
+        .filter_map(|row| {
+            let objectness = row[4];
+            let (class, score) = match best_class(row.iter().skip(first_class_column).copied()) {
+                Some((class, class_score)) => (class, objectness * class_score),
+                None => (0, objectness),
+            };
+            if score <= min_confidence {
+                return None;
+            }
+
+            let (cx, cy, w, h) = (row[0], row[1], row[2], row[3]);
+            let bbox = [
+                (cx - w / 2.0) / width,
+                (cy - h / 2.0) / height,
+                (cx + w / 2.0) / width,
+                (cy + h / 2.0) / height,
+            ];
+            let points = (landmarks > 0).then(|| {
+                to_points(
+                    row.iter().skip(5).take(2 * landmarks).copied(),
+                    width,
+                    height,
+                )
+            });
+
+            Some((bbox, score, class, points))
+        })
+        .collect();
+
+    Ok(candidates)
+}
+
+#[cfg(test)]
+mod test {
+
+    use ndarray::{arr3, Array3};
+
+    use super::*;
+
+    #[test]
+    fn test_descriptor_from_toml() -> Result<()> {
+        let descriptor = DetectorDescriptor::from_toml(
+            r#"
+            model_path = "plates.onnx"
+            width = 320
+            height = 240
+            channel_order = "bgr"
+            mean = [0.5, 0.5, 0.5]
+            std = [0.25, 0.25, 0.25]
+            min_confidence = 0.7
+            labels = ["plate"]
+            [output]
+            kind = "boxes_scores"
+            scores_output = 1
+            boxes_output = 0
+            "#,
+        )?;
+
+        assert_eq!(descriptor.channel_order, ChannelOrder::Bgr);
+        assert_eq!(descriptor.max_iou, 0.5);
+        assert_eq!(descriptor.min_confidence, 0.7);
+        assert_eq!(descriptor.labels, vec!["plate"]);
+        assert_eq!(
+            descriptor.output,
+            OutputDecoding::BoxesScores {
+                scores_output: 1,
+                boxes_output: 0,
+                first_class_column: 1,
+                landmarks_output: None,
+            }
+        );
+
+        let descriptor = DetectorDescriptor::from_toml(
+            r#"
+            model_path = "/models/person.onnx"
+            width = 640
+            height = 640
+
+            [output]
+            kind = "yolo"
+            "#,
+        )?;
+        assert_eq!(
+            descriptor.output,
+            OutputDecoding::Yolo {
+                output: 0,
+                landmarks: 0
+            }
+        );
+        assert_eq!(descriptor.std, [1.0; 3]);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_descriptor_validation() {
+        let invalid = [
+            "model_path = \"m.onnx\"\nwidth = 0\nheight = 240\n[output]\nkind = \"yolo\"",
+            "model_path = \"m.onnx\"\nwidth = 1\nheight = 1\nstd = [1.0, 0.0, 1.0]\n\
+             [output]\nkind = \"yolo\"",
+            "model_path = \"m.onnx\"\nwidth = 1\nheight = 1\nmax_iou = 2.0\n\
+             [output]\nkind = \"yolo\"",
+            "model_path = \"m.onnx\"\nwidth = 1\nheight = 1\n[output]\nkind = \"ssd\"",
+        ];
+
+        for content in invalid {
+            assert!(DetectorDescriptor::from_toml(content).is_err(), "{content}");
+        }
+    }
+
+    #[test]
+    fn test_descriptor_relative_model_path() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        let descriptor_path = dir.path().join("detector.toml");
+        std::fs::write(
+            &descriptor_path,
+            "model_path = \"model.onnx\"\nwidth = 8\nheight = 8\n[output]\nkind = \"yolo\"",
+        )?;
+
+        let descriptor = DetectorDescriptor::from_file(&descriptor_path)?;
+        assert_eq!(descriptor.model_path, dir.path().join("model.onnx"));
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_decode_boxes_scores() -> Result<()> {
+        // Background column first, then two classes
+        let scores = arr3(&[[[0.9, 0.05, 0.05], [0.1, 0.2, 0.7], [0.2, 0.8, 0.0]]]);
+        let boxes = arr3(&[[
+            [0.0, 0.0, 0.1, 0.1],
+            [0.1, 0.2, 0.3, 0.4],
+            [0.5, 0.5, 0.6, 0.6],
+        ]]);
+
+        let candidates = decode_boxes_scores(
+            scores.view().into_dyn(),
+            boxes.view().into_dyn(),
+            None,
+            1,
+            0.5,
+        )?;
+        assert_eq!(
+            candidates,
+            vec![
+                ([0.1, 0.2, 0.3, 0.4], 0.7, 1, None),
+                ([0.5, 0.5, 0.6, 0.6], 0.8, 0, None)
+            ]
+        );
+
+        // Two landmarks per candidate
+        let landmarks = arr3(&[[
+            [0.0, 0.0, 0.0, 0.0],
+            [0.15, 0.25, 0.25, 0.25],
+            [0.52, 0.55, 0.58, 0.55],
+        ]]);
+        let candidates = decode_boxes_scores(
+            scores.view().into_dyn(),
+            boxes.view().into_dyn(),
+            Some(landmarks.view().into_dyn()),
+            1,
+            0.5,
+        )?;
+        assert_eq!(candidates[0].3, Some(vec![[0.15, 0.25], [0.25, 0.25]]));
+        assert_eq!(candidates[1].3, Some(vec![[0.52, 0.55], [0.58, 0.55]]));
+
+        let odd_landmarks = Array3::<f32>::zeros((1, 3, 3));
+        assert!(decode_boxes_scores(
+            scores.view().into_dyn(),
+            boxes.view().into_dyn(),
+            Some(odd_landmarks.view().into_dyn()),
+            1,
+            0.5,
+        )
+        .is_err());
+
+        let boxes = Array3::<f32>::zeros((1, 2, 4));
+        assert!(decode_boxes_scores(
+            scores.view().into_dyn(),
+            boxes.view().into_dyn(),
+            None,
+            1,
+            0.5
+        )
+        .is_err());
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_decoded_labels() -> Result<()> {
+        let descriptor = DetectorDescriptor::from_toml(
+            r#"
+            model_path = "plates.onnx"
+            width = 320
+            height = 240
+            labels = ["plate"]
+
+            [output]
+            kind = "boxes_scores"
+            "#,
+        )?;
+
+        // The background column is skipped, so the plate is the first class
+        let scores = arr3(&[[[0.1, 0.9], [0.9, 0.1]]]);
+        let boxes = arr3(&[[[0.1, 0.2, 0.3, 0.4], [0.5, 0.5, 0.6, 0.6]]]);
+        let candidates = decode_boxes_scores(
+            scores.view().into_dyn(),
+            boxes.view().into_dyn(),
+            None,
+            1,
+            descriptor.min_confidence,
+        )?;
+
+        let labels: Vec<_> = candidates
+            .iter()
+            .map(|(_, _, class_id, _)| descriptor.label(*class_id))
+            .collect();
+        assert_eq!(labels, vec!["plate"]);
+        assert_eq!(descriptor.label(1), "class_1");
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_decode_yolo() -> Result<()> {
+        let output = arr3(&[[
+            // Confident box of class 1 centered in a 100x50 input
+            [50.0, 25.0, 20.0, 10.0, 0.9, 0.1, 1.0],
+            // Low objectness
+            [10.0, 10.0, 4.0, 4.0, 0.2, 1.0, 0.0],
+        ]]);
+
+        let candidates = decode_yolo(output.view().into_dyn(), 0, 100, 50, 0.5)?;
+        assert_eq!(candidates, vec![([0.4, 0.4, 0.6, 0.6], 0.9, 1, None)]);
+
+        // Single face class with one landmark between objectness and class scores
+        let output = arr3(&[[[50.0, 25.0, 20.0, 10.0, 0.9, 45.0, 20.0, 1.0]]]);
+        let candidates = decode_yolo(output.view().into_dyn(), 1, 100, 50, 0.5)?;
+        assert_eq!(
+            candidates,
+            vec![([0.4, 0.4, 0.6, 0.6], 0.9, 0, Some(vec![[0.45, 0.4]]))]
+        );
+        assert!(decode_yolo(output.view().into_dyn(), 2, 100, 50, 0.5).is_err());
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_images_to_tensor_channel_order() -> Result<()> {
+        let mut image = RgbImage::new(2, 2);
+        image
+            .pixels_mut()
+            .for_each(|px| *px = image::Rgb([255, 0, 0]));
+
+        let rgb = images_to_tensor(
+            std::slice::from_ref(&image),
+            2,
+            2,
+            [0.0; 3],
+            [1.0; 3],
+            ChannelOrder::Rgb,
+        )?;
+        let bgr = images_to_tensor(
+            std::slice::from_ref(&image),
+            2,
+            2,
+            [0.0; 3],
+            [1.0; 3],
+            ChannelOrder::Bgr,
+        )?;
+
+        let rgb = rgb.to_array_view::<f32>()?;
+        let bgr = bgr.to_array_view::<f32>()?;
+        assert_eq!(rgb[[0, 0, 0, 0]], 1.0);
+        assert_eq!(rgb[[0, 2, 0, 0]], 0.0);
+        assert_eq!(bgr[[0, 0, 0, 0]], 0.0);
+        assert_eq!(bgr[[0, 2, 0, 0]], 1.0);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_images_to_tensor_batch() -> Result<()> {
+        let black = RgbImage::new(2, 2);
+        let white = RgbImage::from_pixel(2, 2, image::Rgb([255, 255, 255]));
+
+        let batch = images_to_tensor(&[black, white], 2, 2, [0.0; 3], [1.0; 3], ChannelOrder::Rgb)?;
+        assert_eq!(batch.shape(), &[2, 3, 2, 2]);
+
+        let batch = batch.to_array_view::<f32>()?;
+        assert_eq!(batch[[0, 1, 1, 1]], 0.0);
+        assert_eq!(batch[[1, 1, 1, 1]], 1.0);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_batch_item() -> Result<()> {
+        let output = Array3::from_shape_fn((2, 3, 4), |(n, k, _)| (n * 10 + k) as f32);
+        let raw_nn_out: NnOut = tvec!(TValue::from_const(Arc::new(output.into_dyn().into())));
+
+        let item = batch_item(&raw_nn_out, 0, 1)?;
+        assert_eq!(item.shape(), &[1, 3, 4]);
+        assert_eq!(item[[0, 2, 0]], 12.0);
+        assert!(batch_item(&raw_nn_out, 0, 2).is_err());
+        assert!(batch_item(&raw_nn_out, 1, 0).is_err());
+
+        Ok(())
+    }
+}
@@ -2,12 +2,48 @@
 //!
 use std::sync::Arc;
 
-use axum::{body::StreamBody, extract::Query, http::header, response::IntoResponse, Extension};
+use anyhow::Result;
+use axum::{
+    async_trait,
+    body::{Body, Bytes, StreamBody},
+    extract::{
+        ws::{Message, WebSocket, WebSocketUpgrade},
+        FromRequest, Multipart, Query,
+    },
+    http::{header, Request, StatusCode},
+    response::{
+        sse::{Event, KeepAlive, Sse},
+        IntoResponse,
+    },
+    Extension, Json,
+};
 use futures::StreamExt;
-use serde::Deserialize;
+use image::RgbImage;
+use serde::{Deserialize, Serialize};
+use tokio::sync::broadcast::error::RecvError;
 use tokio_stream::wrappers::BroadcastStream;
 
-use crate::{meter::METER, router::FrameRouter};
+use crate::{
+    as_jpeg_stream_item,
+    clips::{list_clips, ClipConfig, ClipInfo},
+    counting::{render_metrics, StreamCounts},
+    detections::{DetectionOutput, FrameDetections},
+    inferer::draw_bboxes_on_image,
+    meter::METER,
+    nn::{Detection, DetectorConfig, SharedModel},
+    recording::{find_frames, play_frames, validate_range, validate_speed, RecordingConfig},
+    router::{FrameRouter, InferOptions},
+    scheduler::{InferScheduler, StreamStats},
+    smoothing::{Smoothing, SmoothingMode},
+    zones::StreamZones,
+    DetectionsReceiver,
+};
+
+/// Default quality of JPEG images returned by the endpoints.
+const DEFAULT_JPEG_QUALITY: u8 = 95;
+
+/// Maximum size of an uploaded image in bytes.
+pub const MAX_UPLOAD_SIZE: usize = 16 * 1024 * 1024;
 
 /// Search parameters available to streams.
 #[derive(Debug, Deserialize)]
@@ -16,6 +52,36 @@ pub struct StreamParams {
     name: Option<String>,
 }
 
+/// Search parameters of the playback of recorded streams.
+#[derive(Debug, Deserialize)]
+pub struct PlaybackParams {
+    #[serde(default)]
+    name: Option<String>,
+    /// Start of the playback in milliseconds since the UNIX epoch.
+    from: u64,
+    /// End of the playback in milliseconds since the UNIX epoch.
+    to: u64,
+    /// Factor of the playback speed, 1 plays in real time.
+    #[serde(default = "default_playback_speed")]
+    speed: f32,
+}
+
+fn default_playback_speed() -> f32 {
+    1.0
+}
+
+/// Reject requests for unanonymized frames if raw streams are disabled.
+fn check_raw_streams(frame_router: &FrameRouter) -> Result<(), (StatusCode, String)> {
+    if frame_router.raw_streams_enabled() {
+        Ok(())
+    } else {
+        Err((
+            StatusCode::FORBIDDEN,
+            "raw streams are disabled, use /anonymized_stream".to_owned(),
+        ))
+    }
+}
+
 /// Health check endpoint.
 pub async fn healthcheck() -> &'static str {
     "healthy"
@@ -25,7 +91,9 @@ pub async fn healthcheck() -> &'static str {
 pub async fn named_stream(
     Extension(frame_router): Extension<Arc<FrameRouter>>,
     Query(params): Query<StreamParams>,
-) -> impl IntoResponse {
+) -> Result<impl IntoResponse, (StatusCode, String)> {
+    check_raw_streams(&frame_router)?;
+
     let name = params.name.unwrap_or_else(|| "unknown".into());
     log::info!("Stream for {} requested", &name);
 
@@ -44,20 +112,85 @@ pub async fn named_stream(
         "multipart/x-mixed-replace; boundary=frame",
     )];
 
-    (headers, body)
+    Ok((headers, body))
 }
 
-pub async fn faces_stream(
+/// Endpoint of streams with anonymized detected regions instead of bounding boxes.
+pub async fn anonymized_stream(
     Extension(frame_router): Extension<Arc<FrameRouter>>,
     Query(params): Query<StreamParams>,
 ) -> impl IntoResponse {
+    let name = params.name.unwrap_or_else(|| "unknown".into());
+    log::info!("Anonymized stream for {} requested", &name);
+
+    // Subscribe to a broadcasted anonymized image stream.
+    let rx = frame_router.get_anonymized_receiver(&name);
+
+    let stream = BroadcastStream::from(rx).map(|x| {
+        METER.tick_infered();
+        x
+    });
+
+    // Set body and headers for multipart streaming
+    let body = StreamBody::new(stream);
+    let headers = [(
+        header::CONTENT_TYPE,
+        "multipart/x-mixed-replace; boundary=frame",
+    )];
+
+    (headers, body)
+}
+
+/// Search parameters available to infered streams.
+#[derive(Debug, Deserialize)]
+pub struct FaceStreamParams {
+    #[serde(default)]
+    name: Option<String>,
+    /// Maximum number of frames per second to infer.
+    #[serde(default)]
+    max_fps: Option<f32>,
+    /// Send every frame, drawing the latest detections on frames which are not infered.
+    #[serde(default)]
+    reuse_detections: bool,
+    /// Smooth the bounding boxes of tracked objects with this filter.
+    #[serde(default)]
+    smoothing: Option<SmoothingMode>,
+    /// Strength of the smoothing between 0 and 1, exclusive.
+    #[serde(default = "default_smoothing_strength")]
+    smoothing_strength: f32,
+}
+
+fn default_smoothing_strength() -> f32 {
+    0.5
+}
+
+pub async fn faces_stream(
+    Extension(frame_router): Extension<Arc<FrameRouter>>,
+    Query(params): Query<FaceStreamParams>,
+) -> Result<impl IntoResponse, (StatusCode, String)> {
+    let smoothing = params
+        .smoothing
+        .map(|mode| Smoothing::new(mode, params.smoothing_strength))
+        .transpose()
+        .map_err(|err| (StatusCode::BAD_REQUEST, err.to_string()))?;
+    let options = InferOptions::default()
+        .with_max_fps(params.max_fps)
+        .map_err(|err| (StatusCode::BAD_REQUEST, err.to_string()))?
+        .with_reuse_detections(params.reuse_detections)
+        .with_smoothing(smoothing);
+
+    check_raw_streams(&frame_router)?;
+
     let name = params.name.unwrap_or_else(|| "unknown".into());
     log::info!("Infered stream for {} requested", &name);
 
     // Subscribe to a broadcasted received image stream.
     let rx = frame_router.get_infered_receiver(&name);
+    let viewer = frame_router.set_infer_options(&name, options);
 
-    let stream = BroadcastStream::from(rx).map(|x| {
+    // The options of the viewer apply as long as its stream is alive
+    let stream = BroadcastStream::from(rx).map(move |x| {
+        let _viewer = &viewer;
         METER.tick_infered();
         x
     });
@@ -69,5 +202,462 @@ pub async fn faces_stream(
         "multipart/x-mixed-replace; boundary=frame",
     )];
 
-    (headers, body)
+    Ok((headers, body))
+}
+
+/// Endpoint of Server-Sent Events with the detections of every infered frame.
+pub async fn detections_events(
+    Extension(frame_router): Extension<Arc<FrameRouter>>,
+    Query(params): Query<StreamParams>,
+) -> impl IntoResponse {
+    let name = params.name.unwrap_or_else(|| "unknown".into());
+    log::info!("Detection events for {} requested", &name);
+
+    // Subscribe to the broadcasted detections of a stream. Lagging receivers skip frames.
+    let rx = frame_router.get_detections_receiver(&name);
+
+    let stream = BroadcastStream::from(rx).filter_map(|x| async move {
+        x.ok()
+            .map(|frame_detections: FrameDetections| Event::default().json_data(frame_detections))
+    });
+
+    Sse::new(stream).keep_alive(KeepAlive::default())
+}
+
+/// WebSocket endpoint pushing infered frames together with their detections.
+///
+/// Every infered frame is sent as a binary message consisting of the big-endian `u64` sequence
+/// number followed by the JPEG data. It is followed by a text message with the detections as
+/// JSON, which carries the same sequence number in its `seq` field.
+pub async fn frames_with_detections_ws(
+    ws: WebSocketUpgrade,
+    Extension(frame_router): Extension<Arc<FrameRouter>>,
+    Query(params): Query<StreamParams>,
+) -> Result<impl IntoResponse, (StatusCode, String)> {
+    check_raw_streams(&frame_router)?;
+
+    let name = params.name.unwrap_or_else(|| "unknown".into());
+    log::info!("WebSocket for {} requested", &name);
+
+    // Subscribe before upgrading so that the stream is infered right away.
+    let rx = frame_router.get_detections_receiver(&name);
+
+    Ok(ws.on_upgrade(move |socket| send_frames_with_detections(socket, rx)))
+}
+
+async fn send_frames_with_detections(mut socket: WebSocket, mut rx: DetectionsReceiver) {
+    loop {
+        let frame_detections = match rx.recv().await {
+            Ok(frame_detections) => frame_detections,
+            Err(RecvError::Lagged(_)) => continue,
+            Err(RecvError::Closed) => break,
+        };
+
+        let frame_msg =
+            Message::Binary(frame_message(frame_detections.seq, &frame_detections.jpeg));
+        let detections_msg = match serde_json::to_string(&frame_detections) {
+            Ok(json) => Message::Text(json),
+            Err(err) => {
+                log::error!("Failed to serialize detections: {err}");
+                break;
+            }
+        };
+
+        if socket.send(frame_msg).await.is_err() || socket.send(detections_msg).await.is_err() {
+            // Client disconnected
+            break;
+        }
+    }
+}
+
+/// Prefix JPEG data with the big-endian sequence number of the frame.
+fn frame_message(seq: u64, jpeg: &[u8]) -> Vec<u8> {
+    [&seq.to_be_bytes()[..], jpeg].concat()
+}
+
+/// Image uploaded either as raw request body or as first field of a multipart form.
+pub struct UploadedImage(pub RgbImage);
+
+#[async_trait]
+impl<S> FromRequest<S, Body> for UploadedImage
+where
+    S: Send + Sync,
+{
+    type Rejection = (StatusCode, String);
+
+    async fn from_request(req: Request<Body>, state: &S) -> Result<Self, Self::Rejection> {
+        let is_multipart = req
+            .headers()
+            .get(header::CONTENT_TYPE)
+            .and_then(|value| value.to_str().ok())
+            .map(|value| value.starts_with("multipart/form-data"))
+            .unwrap_or(false);
+
+        let data = if is_multipart {
+            let mut multipart = Multipart::from_request(req, state)
+                .await
+                .map_err(|err| (StatusCode::BAD_REQUEST, err.to_string()))?;
+            let field = multipart
+                .next_field()
+                .await
+                .map_err(|err| (StatusCode::BAD_REQUEST, err.to_string()))?
+                .ok_or_else(|| (StatusCode::BAD_REQUEST, "no image in form".to_owned()))?;
+            field
+                .bytes()
+                .await
+                .map_err(|err| (StatusCode::BAD_REQUEST, err.to_string()))?
+        } else {
+            Bytes::from_request(req, state)
+                .await
+                .map_err(|err| (StatusCode::BAD_REQUEST, err.to_string()))?
+        };
+
+        let image = image::load_from_memory(&data)
+            .map_err(|err| (StatusCode::UNSUPPORTED_MEDIA_TYPE, err.to_string()))?
+            .to_rgb8();
+
+        Ok(Self(image))
+    }
+}
+
+/// Detections of an uploaded image.
+#[derive(Debug, Serialize)]
+pub struct InferResponse {
+    pub width: u32,
+    pub height: u32,
+    pub detections: Vec<DetectionOutput>,
+}
+
+/// Run inference on an uploaded image with the model on a blocking thread.
+async fn run_model(
+    model: SharedModel,
+    image: RgbImage,
+) -> Result<(RgbImage, Vec<Detection>), (StatusCode, String)> {
+    tokio::task::spawn_blocking(move || {
+        let detections = model.run(&image)?;
+        Ok::<_, anyhow::Error>((image, detections))
+    })
+    .await
+    .map_err(|err| (StatusCode::INTERNAL_SERVER_ERROR, err.to_string()))?
+    .map_err(|err| (StatusCode::INTERNAL_SERVER_ERROR, err.to_string()))
+}
+
+/// Endpoint with the counts of scheduled and dropped frames of every infered stream.
+pub async fn stream_stats(
+    Extension(scheduler): Extension<Arc<InferScheduler>>,
+) -> Json<Vec<StreamStats>> {
+    Json(scheduler.stats())
+}
+
+/// Endpoint of the zone and line counts of all streams.
+pub async fn stream_counts(
+    Extension(frame_router): Extension<Arc<FrameRouter>>,
+) -> Json<Vec<StreamCounts>> {
+    Json(frame_router.counts())
+}
+
+/// Endpoint of the counts in the Prometheus text format.
+pub async fn metrics(Extension(frame_router): Extension<Arc<FrameRouter>>) -> impl IntoResponse {
+    let headers = [(header::CONTENT_TYPE, "text/plain; version=0.0.4")];
+    (headers, render_metrics(&frame_router.counts()))
+}
+
+/// Endpoint listing the recorded clips, of a single stream if a name is given.
+pub async fn recorded_clips(
+    Extension(clip_config): Extension<Option<Arc<ClipConfig>>>,
+    Query(params): Query<StreamParams>,
+) -> Result<Json<Vec<ClipInfo>>, (StatusCode, String)> {
+    let clip_config = clip_config.ok_or_else(|| {
+        (
+            StatusCode::NOT_FOUND,
+            "clip recording is disabled".to_owned(),
+        )
+    })?;
+
+    list_clips(&clip_config.dir, params.name.as_deref())
+        .map(Json)
+        .map_err(|err| (StatusCode::INTERNAL_SERVER_ERROR, format!("{err:#}")))
+}
+
+/// Endpoint playing back the recorded frames of a stream between two times.
+pub async fn playback(
+    Extension(frame_router): Extension<Arc<FrameRouter>>,
+    Extension(recording_config): Extension<Option<Arc<RecordingConfig>>>,
+    Query(params): Query<PlaybackParams>,
+) -> Result<impl IntoResponse, (StatusCode, String)> {
+    check_raw_streams(&frame_router)?;
+    let recording_config = recording_config.ok_or_else(|| {
+        (
+            StatusCode::NOT_FOUND,
+            "stream recording is disabled".to_owned(),
+        )
+    })?;
+    let speed =
+        validate_speed(params.speed).map_err(|err| (StatusCode::BAD_REQUEST, err.to_string()))?;
+    validate_range(params.from, params.to)
+        .map_err(|err| (StatusCode::BAD_REQUEST, err.to_string()))?;
+
+    let name = params.name.unwrap_or_else(|| "unknown".into());
+    log::info!(
+        "Playback of {} from {} to {} requested",
+        &name,
+        params.from,
+        params.to
+    );
+
+    let (from, to) = (params.from, params.to);
+    let frames =
+        tokio::task::spawn_blocking(move || find_frames(&recording_config, &name, from, to))
+            .await
+            .map_err(anyhow::Error::from)
+            .and_then(|frames| frames)
+            .map_err(|err| (StatusCode::INTERNAL_SERVER_ERROR, format!("{err:#}")))?;
+    let stream = play_frames(frames, speed).map(|data| data.map(|data| as_jpeg_stream_item(&data)));
+
+    // Set body and headers for multipart streaming
+    let body = StreamBody::new(stream);
+    let headers = [(
+        header::CONTENT_TYPE,
+        "multipart/x-mixed-replace; boundary=frame",
+    )];
+
+    Ok((headers, body))
+}
+
+/// Endpoint of the zones of a stream.
+pub async fn get_zones(
+    Extension(frame_router): Extension<Arc<FrameRouter>>,
+    Query(params): Query<StreamParams>,
+) -> Json<StreamZones> {
+    let name = params.name.unwrap_or_else(|| "unknown".into());
+    Json(frame_router.get_zones(&name))
+}
+
+/// Endpoint to replace the zones of a stream, an empty list removes them.
+pub async fn put_zones(
+    Extension(frame_router): Extension<Arc<FrameRouter>>,
+    Query(params): Query<StreamParams>,
+    Json(zones): Json<StreamZones>,
+) -> Result<Json<StreamZones>, (StatusCode, String)> {
+    zones
+        .validate()
+        .map_err(|err| (StatusCode::UNPROCESSABLE_ENTITY, err.to_string()))?;
+
+    let name = params.name.unwrap_or_else(|| "unknown".into());
+    log::info!("Updated zones of {} to {:?}", &name, zones);
+    frame_router.set_zones(&name, zones);
+
+    Ok(Json(frame_router.get_zones(&name)))
+}
+
+/// Endpoint to infer faces on an uploaded JPEG or PNG image.
+pub async fn infer_image(
+    Extension(model): Extension<SharedModel>,
+    UploadedImage(image): UploadedImage,
+) -> Result<Json<InferResponse>, (StatusCode, String)> {
+    let (image, detections) = run_model(model, image).await?;
+    let (width, height) = image.dimensions();
+
+    let detections = detections
+        .into_iter()
+        .map(|detection| DetectionOutput::new(detection, width, height))
+        .collect();
+
+    Ok(Json(InferResponse {
+        width,
+        height,
+        detections,
+    }))
+}
+
+/// Output formats of annotated images.
+#[derive(Clone, Copy, Debug, Default, Deserialize, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum OutputFormat {
+    #[default]
+    Jpeg,
+    Png,
+}
+
+/// Search parameters available to the annotated inference endpoint.
+#[derive(Debug, Deserialize)]
+pub struct AnnotatedParams {
+    #[serde(default)]
+    format: OutputFormat,
+    #[serde(default = "default_jpeg_quality")]
+    quality: u8,
+}
+
+fn default_jpeg_quality() -> u8 {
+    DEFAULT_JPEG_QUALITY
+}
+
+/// Encode an image in the given format and return it with its content type.
+fn encode_image(
+    image: &RgbImage,
+    format: OutputFormat,
+    quality: u8,
+) -> Result<(&'static str, Vec<u8>)> {
+    match format {
+        OutputFormat::Jpeg => {
+            let buf = turbojpeg::compress_image(image, quality as i32, turbojpeg::Subsamp::Sub2x2)?;
+            Ok(("image/jpeg", buf.to_vec()))
+        }
+        OutputFormat::Png => {
+            let mut buf = Vec::new();
+            image.write_to(
+                &mut std::io::Cursor::new(&mut buf),
+                image::ImageOutputFormat::Png,
+            )?;
+            Ok(("image/png", buf))
+        }
+    }
+}
+
+/// Endpoint to infer faces on an uploaded JPEG or PNG image and return it annotated.
+pub async fn infer_annotated_image(
+    Extension(model): Extension<SharedModel>,
+    Query(params): Query<AnnotatedParams>,
+    UploadedImage(image): UploadedImage,
+) -> Result<impl IntoResponse, (StatusCode, String)> {
+    if !(1..=100).contains(&params.quality) {
+        return Err((
+            StatusCode::BAD_REQUEST,
+            "quality has to be in the range 1..=100".to_owned(),
+        ));
+    }
+
+    let (image, detections) = run_model(model, image).await?;
+    let (width, height) = image.dimensions();
+    let image = draw_bboxes_on_image(image, &detections, width, height);
+
+    let (content_type, buf) = encode_image(&image, params.format, params.quality)
+        .map_err(|err| (StatusCode::INTERNAL_SERVER_ERROR, err.to_string()))?;
+
+    Ok(([(header::CONTENT_TYPE, content_type)], buf))
+}
+
+/// Endpoint to get the current thresholds of the detector.
+pub async fn get_detector_config(Extension(model): Extension<SharedModel>) -> Json<DetectorConfig> {
+    Json(model.config())
+}
+
+/// Endpoint to update the thresholds of the detector, applied from the next frame on.
+pub async fn put_detector_config(
+    Extension(model): Extension<SharedModel>,
+    Json(config): Json<DetectorConfig>,
+) -> Result<Json<DetectorConfig>, (StatusCode, String)> {
+    model
+        .set_config(config)
+        .map_err(|err| (StatusCode::UNPROCESSABLE_ENTITY, err.to_string()))?;
+    log::info!("Updated detector config to {:?}", config);
+
+    Ok(Json(model.config()))
+}
+
+#[cfg(test)]
+mod test {
+
+    use axum::extract::FromRequestParts;
+
+    use super::*;
+
+    fn png_data(width: u32, height: u32) -> Vec<u8> {
+        let mut data = Vec::new();
+        RgbImage::new(width, height)
+            .write_to(
+                &mut std::io::Cursor::new(&mut data),
+                image::ImageOutputFormat::Png,
+            )
+            .unwrap();
+        data
+    }
+
+    async fn query_params<T>(uri: &str) -> T
+    where
+        T: serde::de::DeserializeOwned + Send,
+    {
+        let (mut parts, _body) = Request::get(uri).body(()).unwrap().into_parts();
+        let Query(params) = Query::<T>::from_request_parts(&mut parts, &())
+            .await
+            .unwrap();
+        params
+    }
+
+    #[tokio::test]
+    async fn test_annotated_params() {
+        let params: AnnotatedParams = query_params("/infer/annotated").await;
+        assert_eq!(params.format, OutputFormat::Jpeg);
+        assert_eq!(params.quality, DEFAULT_JPEG_QUALITY);
+
+        let params: AnnotatedParams = query_params("/infer/annotated?format=png&quality=50").await;
+        assert_eq!(params.format, OutputFormat::Png);
+        assert_eq!(params.quality, 50);
+    }
+
+    #[test]
+    fn test_frame_message() {
+        let msg = frame_message(258, &[0xff, 0xd8]);
+        assert_eq!(msg, vec![0, 0, 0, 0, 0, 0, 1, 2, 0xff, 0xd8]);
+    }
+
+    #[test]
+    fn test_encode_image() {
+        let image = RgbImage::new(64, 48);
+
+        for (format, expected_content_type) in [
+            (OutputFormat::Jpeg, "image/jpeg"),
+            (OutputFormat::Png, "image/png"),
+        ] {
+            let (content_type, buf) = encode_image(&image, format, 80).unwrap();
+            assert_eq!(content_type, expected_content_type);
+
+            let decoded = image::load_from_memory(&buf).unwrap();
+            assert_eq!((decoded.width(), decoded.height()), (64, 48));
+        }
+    }
+
+    #[tokio::test]
+    async fn test_uploaded_image_raw_body() {
+        let req = Request::builder()
+            .header(header::CONTENT_TYPE, "image/png")
+            .body(Body::from(png_data(64, 48)))
+            .unwrap();
+
+        let UploadedImage(image) = UploadedImage::from_request(req, &()).await.unwrap();
+        assert_eq!(image.dimensions(), (64, 48));
+    }
+
+    #[tokio::test]
+    async fn test_uploaded_image_multipart() {
+        let boundary = "X-BOUNDARY";
+        let body = [
+            format!(
+                "--{boundary}\r\nContent-Disposition: form-data; name=\"image\"; \
+                 filename=\"test.png\"\r\nContent-Type: image/png\r\n\r\n"
+            )
+            .into_bytes(),
+            png_data(32, 24),
+            format!("\r\n--{boundary}--\r\n").into_bytes(),
+        ]
+        .concat();
+        let req = Request::builder()
+            .header(
+                header::CONTENT_TYPE,
+                format!("multipart/form-data; boundary={boundary}"),
+            )
+            .body(Body::from(body))
+            .unwrap();
+
+        let UploadedImage(image) = UploadedImage::from_request(req, &()).await.unwrap();
+        assert_eq!(image.dimensions(), (32, 24));
+    }
+
+    #[tokio::test]
+    async fn test_uploaded_image_invalid_data() {
+        let req = Request::builder().body(Body::from("no image")).unwrap();
+
+        let (status, _) = UploadedImage::from_request(req, &()).await.err().unwrap();
+        assert_eq!(status, StatusCode::UNSUPPORTED_MEDIA_TYPE);
+    }
 }
@@ -1,71 +1,253 @@
+use std::{
+    sync::Arc,
+    thread::JoinHandle,
+    time::{Duration, Instant},
+};
+
 use anyhow::Result;
 use image::{Rgb, RgbImage};
 use imageproc::{
-    drawing::{draw_hollow_rect, draw_text},
+    drawing::{draw_filled_circle, draw_hollow_rect, draw_text},
     rect::Rect,
 };
 use lazy_static::lazy_static;
 
 use crate::{
-    nn::{Bbox, InferModel, UltrafaceModel},
-    StaticImageReceiver,
+    detections::FrameDetections,
+    meter::{WorkerMeter, METER},
+    nn::{Detection, SharedModel},
+    scheduler::InferScheduler,
+    zones::{draw_zones_on_image, StreamZones},
+    StaticImage,
 };
 
-use super::as_jpeg_stream_item;
+use super::{as_infered_stream_item, as_jpeg_stream_item};
 
+/// Pool of inference workers on dedicated threads, sharing one model and one scheduler.
 pub struct Inferer {
-    infer_rx: StaticImageReceiver,
-    model: UltrafaceModel,
+    scheduler: Arc<InferScheduler>,
+    model: SharedModel,
+    num_workers: usize,
+    batch_size: usize,
+    max_batch_wait: Duration,
 }
 
 impl Inferer {
-    pub async fn new(infer_rx: StaticImageReceiver) -> Self {
-        let model = UltrafaceModel::new(crate::nn::UltrafaceVariant::W320H240, 0.5, 0.5)
-            .await
-            .expect("failed to initialized model");
-        Self { infer_rx, model }
-    }
-
-    pub async fn run(&self) {
-        loop {
-            if let Some(recv_ref) = self.infer_rx.recv_ref().await {
-                let width = recv_ref.0;
-                let height = recv_ref.1;
-
-                let image: RgbImage = turbojpeg::decompress_image(recv_ref.2.as_slice())
-                    .expect("failed to decompress");
-                if let Ok(bboxes_with_confidences) = self.infer_faces(&image) {
-                    let frame = draw_bboxes_on_image(image, bboxes_with_confidences, width, height);
-                    let buf = turbojpeg::compress_image(&frame, 95, turbojpeg::Subsamp::Sub2x2)
-                        .expect("failed to compress");
-                    recv_ref
-                        .3
-                        .as_ref()
+    pub fn new(scheduler: Arc<InferScheduler>, model: SharedModel, num_workers: usize) -> Self {
+        Self {
+            scheduler,
+            model,
+            num_workers: num_workers.max(1),
+            batch_size: 1,
+            max_batch_wait: Duration::ZERO,
+        }
+    }
+
+    /// Infer up to `batch_size` frames at once, waiting at most `max_wait` for a batch to fill.
+    pub fn with_batching(mut self, batch_size: usize, max_wait: Duration) -> Self {
+        self.batch_size = batch_size.max(1);
+        self.max_batch_wait = max_wait;
+        self
+    }
+
+    /// Spawn the inference workers, each on its own thread.
+    pub fn spawn(self) -> Result<Vec<JoinHandle<()>>> {
+        (0..self.num_workers)
+            .map(|index| {
+                let worker = Worker {
+                    scheduler: self.scheduler.clone(),
+                    model: self.model.clone(),
+                    batch_size: self.batch_size,
+                    max_batch_wait: self.max_batch_wait,
+                    meter: METER.register_worker(),
+                };
+                let handle = std::thread::Builder::new()
+                    .name(format!("inferer-{index}"))
+                    .spawn(move || worker.run())?;
+
+                Ok(handle)
+            })
+            .collect()
+    }
+}
+
+/// Single inference worker, running the model synchronously on its own thread.
+struct Worker {
+    scheduler: Arc<InferScheduler>,
+    model: SharedModel,
+    batch_size: usize,
+    max_batch_wait: Duration,
+    meter: Arc<WorkerMeter>,
+}
+
+impl Worker {
+    fn run(&self) {
+        while let Some(batch) = self
+            .scheduler
+            .next_batch(self.batch_size, self.max_batch_wait)
+        {
+            let start = Instant::now();
+            self.process(&batch);
+            self.meter.record(batch.len() as u64, start.elapsed());
+
+            // Serve the streams again only after their results are published, to keep them in order
+            for frame in batch.iter() {
+                self.scheduler.done(frame.stream_id);
+            }
+        }
+    }
+
+    fn process(&self, batch: &[StaticImage]) {
+        let (frames, images): (Vec<_>, Vec<_>) = batch
+            .iter()
+            .filter_map(
+                |recv_ref| match turbojpeg::decompress_image(&recv_ref.data[..]) {
+                    Ok(image) => Some((recv_ref, image)),
+                    Err(err) => {
+                        log::warn!("Failed to decompress frame: {err}");
+                        None
+                    }
+                },
+            )
+            .unzip();
+
+        let detections = match self.infer(&images) {
+            Ok(detections) => detections,
+            Err(err) => {
+                log::warn!("Failed to infer batch of {} frames: {err}", images.len());
+                return;
+            }
+        };
+
+        for ((recv_ref, image), mut detections) in frames.into_iter().zip(images).zip(detections) {
+            // Faces outside of the zones are dropped from the outputs, but never left unanonymized
+            let unfiltered = recv_ref.anonymized_tx.is_some().then(|| detections.clone());
+            if let Some(zones) = recv_ref.zones.as_ref() {
+                zones.apply(&mut detections);
+            }
+            if let Some(tracker) = recv_ref.tracker.as_ref() {
+                let mut tracker = tracker.lock().unwrap();
+                // Stale frames would be published out of order and without track ids
+                if !tracker.update(
+                    recv_ref.seq,
+                    recv_ref.timestamp_ms,
+                    &mut detections,
+                    recv_ref.smoothing.as_ref(),
+                ) {
+                    log::debug!("Skipping stale frame {} of a tracked stream", recv_ref.seq);
+                    continue;
+                }
+
+                if let (Some(counter), Some(zones)) = (&recv_ref.counter, &recv_ref.zones) {
+                    counter
+                        .lock()
                         .unwrap()
-                        .send(as_jpeg_stream_item(&buf))
-                        .ok();
+                        .update(zones, &detections, &tracker.track_ids());
                 }
             }
+            let anonymized = unfiltered.as_deref().unwrap_or(&detections);
+            self.publish(recv_ref, image, &detections, anonymized);
+        }
+    }
+
+    /// Publish the detections and the infered frame of a stream.
+    ///
+    /// The regions of `anonymized` are anonymized, including detections filtered out by zones.
+    fn publish(
+        &self,
+        recv_ref: &StaticImage,
+        image: RgbImage,
+        detections: &[Detection],
+        anonymized: &[Detection],
+    ) {
+        let width = recv_ref.width;
+        let height = recv_ref.height;
+
+        if let Some(latest_detections) = recv_ref.latest_detections.as_ref() {
+            *latest_detections.lock().unwrap() = detections.to_vec();
+        }
+
+        if let Some(detections_tx) = recv_ref.detections_tx.as_ref() {
+            detections_tx
+                .send(FrameDetections::new(
+                    recv_ref.seq,
+                    recv_ref.timestamp_ms,
+                    width,
+                    height,
+                    detections,
+                    recv_ref.data.clone(),
+                ))
+                .ok();
+        }
+
+        if let Some(anonymized_tx) = recv_ref.anonymized_tx.as_ref() {
+            let mut frame = image.clone();
+            recv_ref.anonymization.apply(&mut frame, anonymized);
+            let buf = turbojpeg::compress_image(&frame, 95, turbojpeg::Subsamp::Sub2x2)
+                .expect("failed to compress");
+            anonymized_tx.send(as_jpeg_stream_item(&buf)).ok();
+        }
+
+        if let Some(infered_tx) = recv_ref.infered_tx.as_ref() {
+            let mut frame = draw_bboxes_on_image(image, detections, width, height);
+            if let Some(zones) = recv_ref.zones.as_ref() {
+                draw_zones_on_image(&mut frame, zones);
+            }
+            let buf = turbojpeg::compress_image(&frame, 95, turbojpeg::Subsamp::Sub2x2)
+                .expect("failed to compress");
+            infered_tx.send(as_infered_stream_item(&buf, true)).ok();
         }
     }
 
-    fn infer_faces(&self, frame: &RgbImage) -> Result<Vec<(Bbox, f32)>> {
-        self.model.run(frame)
+    fn infer(&self, frames: &[RgbImage]) -> Result<Vec<Vec<Detection>>> {
+        self.model.run_batch(frames)
     }
 }
 
-/// Draw bounding boxes with confidence scores on the image.
-fn draw_bboxes_on_image(
+/// Draw detections and zones on a JPEG frame and encode it again.
+pub(crate) fn annotate_jpeg(
+    jpeg: &[u8],
+    detections: &[Detection],
+    zones: Option<&StreamZones>,
+    width: u32,
+    height: u32,
+) -> Result<Vec<u8>> {
+    let image: RgbImage = turbojpeg::decompress_image(jpeg)?;
+    let mut frame = draw_bboxes_on_image(image, detections, width, height);
+    if let Some(zones) = zones {
+        draw_zones_on_image(&mut frame, zones);
+    }
+    let buf = turbojpeg::compress_image(&frame, 95, turbojpeg::Subsamp::Sub2x2)?;
+
+    Ok(buf.to_vec())
+}
+
+/// Colors of the bounding boxes by class, repeating for classes beyond the palette.
+const CLASS_COLORS: [[u8; 3]; 6] = [
+    [0, 255, 0],
+    [255, 0, 0],
+    [0, 128, 255],
+    [255, 255, 0],
+    [255, 0, 255],
+    [0, 255, 255],
+];
+
+/// Radius of the dots marking landmarks.
+const LANDMARK_RADIUS: i32 = 2;
+
+/// Draw bounding boxes with labels, scores and landmarks on the image.
+pub(crate) fn draw_bboxes_on_image(
     mut frame: RgbImage,
-    bboxes_with_confidences: Vec<([f32; 4], f32)>,
+    detections: &[Detection],
     width: u32,
     height: u32,
 ) -> RgbImage {
     let (width, height) = (width as f32, height as f32);
 
-    let color = Rgb::from([0, 255, 0]);
+    for detection in detections.iter() {
+        let bbox = &detection.bbox;
+        let color = Rgb::from(CLASS_COLORS[detection.class_id % CLASS_COLORS.len()]);
 
-    for (bbox, confidence) in bboxes_with_confidences.iter() {
         // Coordinates of top-left and bottom-right points
         // Coordinate frame basis is on the top left corner
         let (x_tl, y_tl) = (bbox[0] * width, bbox[1] * height);
@@ -73,10 +255,10 @@ fn draw_bboxes_on_image(
         let rect_width = x_br - x_tl;
         let rect_height = y_br - y_tl;
 
-        let face_rect =
+        let rect =
             Rect::at(x_tl as i32, y_tl as i32).of_size(rect_width as u32, rect_height as u32);
 
-        frame = draw_hollow_rect(&frame, face_rect, color);
+        frame = draw_hollow_rect(&frame, rect, color);
         frame = draw_text(
             &frame,
             color,
@@ -84,13 +266,27 @@ fn draw_bboxes_on_image(
             y_tl as i32,
             rusttype::Scale { x: 16.0, y: 16.0 },
             &DEJAVU_MONO,
-            &format!("{:.2}%", confidence * 100.0),
+            &caption(detection),
         );
+
+        for point in detection.landmarks.iter().flatten() {
+            let center = ((point[0] * width) as i32, (point[1] * height) as i32);
+            frame = draw_filled_circle(&frame, center, LANDMARK_RADIUS, color);
+        }
     }
 
     frame
 }
 
+/// Caption of a detection with its label, score and track id if it is tracked.
+fn caption(detection: &Detection) -> String {
+    let caption = format!("{} {:.2}%", detection.label, detection.score * 100.0);
+    match detection.track_id {
+        Some(track_id) => format!("{caption} #{track_id}"),
+        None => caption,
+    }
+}
+
 lazy_static! {
     static ref DEJAVU_MONO: rusttype::Font<'static> = {
         let font_data: &[u8] = include_bytes!("../../resources/DejaVuSansMono.ttf");
@@ -99,3 +295,236 @@ lazy_static! {
         font
     };
 }
+
+#[cfg(test)]
+mod test {
+
+    use std::{collections::HashSet, sync::Mutex};
+
+    use bytes::Bytes;
+
+    use super::*;
+    use crate::{
+        anonymize::{Anonymization, AnonymizeMode},
+        broadcast_channel, jpeg_from_stream_item,
+        nn::{DetectorConfig, InferModel},
+        tracking::{Tracker, TrackerConfig},
+        zones::Zone,
+        DetectionsSender,
+    };
+
+    /// Model recording the threads it runs on and the sizes of the batches.
+    #[derive(Default)]
+    struct RecordingModel {
+        threads: Mutex<HashSet<String>>,
+        batch_sizes: Mutex<Vec<usize>>,
+    }
+
+    impl InferModel for RecordingModel {
+        fn run(&self, _input: &RgbImage) -> Result<Vec<Detection>> {
+            let name = std::thread::current().name().unwrap_or_default().to_owned();
+            self.threads.lock().unwrap().insert(name);
+            std::thread::sleep(Duration::from_millis(50));
+
+            Ok(vec![Detection::new(0, "face", 0.9, [0.1, 0.1, 0.5, 0.5])])
+        }
+
+        fn run_batch(&self, inputs: &[RgbImage]) -> Result<Vec<Vec<Detection>>> {
+            self.batch_sizes.lock().unwrap().push(inputs.len());
+            inputs.iter().map(|input| self.run(input)).collect()
+        }
+
+        fn config(&self) -> DetectorConfig {
+            DetectorConfig::new(0.5, 0.5).unwrap()
+        }
+
+        fn set_config(&self, _config: DetectorConfig) -> Result<()> {
+            Ok(())
+        }
+    }
+
+    fn test_frame(seq: u64, detections_tx: &DetectionsSender) -> StaticImage {
+        let jpeg = turbojpeg::compress_image(&RgbImage::new(32, 24), 90, turbojpeg::Subsamp::None)
+            .unwrap();
+
+        StaticImage {
+            width: 32,
+            height: 24,
+            data: Bytes::copy_from_slice(&jpeg),
+            seq,
+            detections_tx: Some(detections_tx.clone()),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn test_worker_pool() -> Result<()> {
+        let model = Arc::new(RecordingModel::default());
+        let scheduler = Arc::new(InferScheduler::new());
+        let handles = Inferer::new(scheduler.clone(), model.clone(), 2).spawn()?;
+
+        let (detections_tx, mut detections_rx) = broadcast_channel();
+        for seq in 0..4 {
+            scheduler.push(seq, &format!("cam-{seq}"), test_frame(seq, &detections_tx));
+        }
+
+        let mut seqs: Vec<_> = (0..4)
+            .map(|_| {
+                futures::executor::block_on(detections_rx.recv())
+                    .unwrap()
+                    .seq
+            })
+            .collect();
+        seqs.sort();
+        assert_eq!(seqs, vec![0, 1, 2, 3]);
+        assert_eq!(
+            *model.threads.lock().unwrap(),
+            HashSet::from(["inferer-0".to_owned(), "inferer-1".to_owned()])
+        );
+
+        // Workers stop once the scheduler is closed
+        scheduler.close();
+        for handle in handles {
+            handle.join().unwrap();
+        }
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_worker_pool_keeps_stream_order() -> Result<()> {
+        let model = Arc::new(RecordingModel::default());
+        let scheduler = Arc::new(InferScheduler::new());
+        let handles = Inferer::new(scheduler.clone(), model, 2).spawn()?;
+
+        // New frames of the stream arrive while the previous one is infered
+        let (detections_tx, mut detections_rx) = broadcast_channel();
+        for seq in 0..6 {
+            scheduler.push(1, "cam", test_frame(seq, &detections_tx));
+            std::thread::sleep(Duration::from_millis(20));
+        }
+
+        let mut seqs = vec![];
+        while seqs.last() != Some(&5) {
+            seqs.push(
+                futures::executor::block_on(detections_rx.recv())
+                    .unwrap()
+                    .seq,
+            );
+        }
+        assert!(seqs.windows(2).all(|pair| pair[0] < pair[1]), "{seqs:?}");
+
+        scheduler.close();
+        for handle in handles {
+            handle.join().unwrap();
+        }
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_worker_batching() -> Result<()> {
+        let model = Arc::new(RecordingModel::default());
+        let scheduler = Arc::new(InferScheduler::new());
+
+        // Three frames are waiting, the batch is not filled up within the wait time
+        let (detections_tx, mut detections_rx) = broadcast_channel();
+        for seq in 0..3 {
+            scheduler.push(seq, &format!("cam-{seq}"), test_frame(seq, &detections_tx));
+        }
+        let handles = Inferer::new(scheduler.clone(), model.clone(), 1)
+            .with_batching(4, Duration::from_millis(20))
+            .spawn()?;
+
+        let seqs: Vec<_> = (0..3)
+            .map(|_| {
+                futures::executor::block_on(detections_rx.recv())
+                    .unwrap()
+                    .seq
+            })
+            .collect();
+        assert_eq!(seqs, vec![0, 1, 2]);
+        assert_eq!(*model.batch_sizes.lock().unwrap(), vec![3]);
+
+        scheduler.close();
+        for handle in handles {
+            handle.join().unwrap();
+        }
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_anonymize_faces_filtered_by_zones() -> Result<()> {
+        let model = Arc::new(RecordingModel::default());
+        let worker = Worker {
+            scheduler: Arc::new(InferScheduler::new()),
+            model,
+            batch_size: 1,
+            max_batch_wait: Duration::ZERO,
+            meter: METER.register_worker(),
+        };
+
+        // The face is outside of the only zone and dropped from the detections
+        let (detections_tx, mut detections_rx) = broadcast_channel();
+        let (anonymized_tx, mut anonymized_rx) = broadcast_channel();
+        let white = RgbImage::from_pixel(32, 24, Rgb([255, 255, 255]));
+        let frame = StaticImage {
+            data: Bytes::copy_from_slice(&turbojpeg::compress_image(
+                &white,
+                90,
+                turbojpeg::Subsamp::None,
+            )?),
+            anonymized_tx: Some(anonymized_tx),
+            anonymization: Anonymization::new(AnonymizeMode::Fill, 1.0)?,
+            zones: Some(Arc::new(StreamZones {
+                zones: vec![Zone {
+                    name: "door".to_owned(),
+                    polygon: vec![[0.6, 0.0], [1.0, 0.0], [1.0, 1.0], [0.6, 1.0]],
+                }],
+                filter: true,
+                ..Default::default()
+            })),
+            ..test_frame(0, &detections_tx)
+        };
+        worker.process(&[frame]);
+
+        assert!(detections_rx.try_recv()?.detections.is_empty());
+
+        // The face is still anonymized
+        let item = anonymized_rx.try_recv()?;
+        let anonymized: RgbImage =
+            turbojpeg::decompress_image(&jpeg_from_stream_item(&item).unwrap())?;
+        assert!(anonymized.get_pixel(9, 7)[0] < 64);
+        assert!(anonymized.get_pixel(28, 20)[0] > 192);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_stale_tracked_frames_are_not_published() -> Result<()> {
+        let worker = Worker {
+            scheduler: Arc::new(InferScheduler::new()),
+            model: Arc::new(RecordingModel::default()),
+            batch_size: 1,
+            max_batch_wait: Duration::ZERO,
+            meter: METER.register_worker(),
+        };
+
+        let (detections_tx, mut detections_rx) = broadcast_channel();
+        let tracker = Arc::new(Mutex::new(Tracker::new(TrackerConfig::new(1, 5, 0.3)?)));
+        let frame = |seq| StaticImage {
+            tracker: Some(tracker.clone()),
+            ..test_frame(seq, &detections_tx)
+        };
+        worker.process(&[frame(2)]);
+        worker.process(&[frame(1)]);
+
+        let detections = detections_rx.try_recv()?;
+        assert_eq!(detections.seq, 2);
+        assert!(detections.detections[0].detection.track_id.is_some());
+        assert!(detections_rx.try_recv().is_err());
+
+        Ok(())
+    }
+}
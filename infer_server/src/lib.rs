@@ -4,18 +4,40 @@
 use std::{
     collections::hash_map::DefaultHasher,
     hash::{Hash, Hasher},
+    sync::{Arc, Mutex},
+    time::Duration,
 };
 
+use anonymize::Anonymization;
+use anyhow::Result;
 use bytes::{Bytes, BytesMut};
+use counting::SharedCounter;
+use detections::FrameDetections;
+use nn::Detection;
+use smoothing::Smoothing;
 use thingbuf::mpsc::{StaticChannel, StaticReceiver, StaticSender};
+use tokio::task::JoinHandle;
+use tracking::SharedTracker;
+use zones::StreamZones;
 
+pub mod anonymize;
+pub mod clips;
+pub mod counting;
 pub mod data_socket;
+pub mod detections;
+pub mod detector;
 pub mod endpoints;
 pub mod inferer;
 pub mod meter;
 pub mod nn;
+pub mod recording;
 pub mod router;
+pub mod scheduler;
+pub mod smoothing;
+pub mod tracking;
 pub mod utils;
+pub mod webhooks;
+pub mod zones;
 
 pub type StaticFrameSender = StaticSender<BytesMut>;
 pub type StaticFrameReceiver = StaticReceiver<BytesMut>;
@@ -25,16 +47,48 @@ pub static INCOMING_FRAMES_CHANNEL: StaticChannel<BytesMut, 200> = StaticChannel
 pub type BroadcastSender = tokio::sync::broadcast::Sender<Bytes>;
 pub type BroadcastReceiver = tokio::sync::broadcast::Receiver<Bytes>;
 
-pub fn broadcast_channel() -> (BroadcastSender, BroadcastReceiver) {
+pub type DetectionsSender = tokio::sync::broadcast::Sender<FrameDetections>;
+pub type DetectionsReceiver = tokio::sync::broadcast::Receiver<FrameDetections>;
+
+pub fn broadcast_channel<T: Clone>() -> (
+    tokio::sync::broadcast::Sender<T>,
+    tokio::sync::broadcast::Receiver<T>,
+) {
     tokio::sync::broadcast::channel(20)
 }
 
-pub type StaticImage = (u32, u32, Vec<u8>, Option<BroadcastSender>);
-
-pub type StaticImageSender = StaticSender<StaticImage>;
-pub type StaticImageReceiver = StaticReceiver<StaticImage>;
+/// Frame to infer together with the channels to publish the results on.
+#[derive(Clone, Debug, Default)]
+pub struct StaticImage {
+    /// Id of the stream of the frame, set when the frame is scheduled.
+    pub stream_id: u64,
+    pub width: u32,
+    pub height: u32,
+    /// JPEG data of the frame, shared with the published results instead of copied.
+    pub data: Bytes,
+    /// Sequence number of the frame in its stream.
+    pub seq: u64,
+    /// Time of receiving the frame in milliseconds since the UNIX epoch.
+    pub timestamp_ms: u64,
+    pub infered_tx: Option<BroadcastSender>,
+    pub detections_tx: Option<DetectionsSender>,
+    /// Latest detections of the stream, kept for frames which are not infered.
+    pub latest_detections: Option<LatestDetections>,
+    /// Tracker of the objects in the stream.
+    pub tracker: Option<SharedTracker>,
+    /// Smoothing of the bounding boxes of tracked objects.
+    pub smoothing: Option<Smoothing>,
+    pub anonymized_tx: Option<BroadcastSender>,
+    /// Anonymization of the detected regions on the anonymized stream.
+    pub anonymization: Anonymization,
+    /// Zones of the stream, filtering and labeling the detections.
+    pub zones: Option<Arc<StreamZones>>,
+    /// Counter of the objects in the zones and crossing the lines of the stream.
+    pub counter: Option<SharedCounter>,
+}
 
-pub static INFER_IMAGES_CHANNEL: StaticChannel<StaticImage, 10> = StaticChannel::new();
+/// Latest detections of a stream, shared between the router and the inference workers.
+pub type LatestDetections = Arc<Mutex<Vec<Detection>>>;
 
 fn hashed<T>(data: T) -> u64
 where
@@ -55,3 +109,80 @@ fn as_jpeg_stream_item(data: &[u8]) -> Bytes {
         .concat(),
     )
 }
+
+/// Extract the JPEG data of a multipart stream item.
+fn jpeg_from_stream_item(item: &Bytes) -> Option<Bytes> {
+    let header_end = item.windows(4).position(|window| window == b"\r\n\r\n")? + 4;
+    let data_end = item.len().checked_sub(4)?;
+    (header_end <= data_end).then(|| item.slice(header_end..data_end))
+}
+
+/// Stream item of an infered frame, marked whether its detections are fresh or reused.
+fn as_infered_stream_item(data: &[u8], fresh: bool) -> Bytes {
+    let detections = if fresh { "fresh" } else { "reused" };
+    Bytes::copy_from_slice(
+        &[
+            format!("--frame\r\nContent-Type: image/jpeg\r\nX-Detections: {detections}\r\n\r\n")
+                .as_bytes(),
+            data,
+            "\r\n\r\n".as_bytes(),
+        ]
+        .concat(),
+    )
+}
+
+/// Run `retention` on a blocking thread right away and then every `interval`.
+///
+/// `retention` returns the number of deleted `items`, which is logged together with failures.
+fn spawn_retention<F>(items: &'static str, interval: Duration, retention: F) -> JoinHandle<()>
+where
+    F: Fn() -> Result<usize> + Send + Sync + 'static,
+{
+    let retention = Arc::new(retention);
+
+    tokio::spawn(async move {
+        let mut interval = tokio::time::interval(interval);
+        loop {
+            interval.tick().await;
+
+            let retention = retention.clone();
+            match tokio::task::spawn_blocking(move || retention()).await {
+                Ok(Ok(0)) => (),
+                Ok(Ok(deleted)) => log::info!("Deleted {deleted} {items}"),
+                Ok(Err(err)) => log::warn!("Failed to enforce the retention of {items}: {err:#}"),
+                Err(err) => log::warn!("Retention of {items} panicked: {err}"),
+            }
+        }
+    })
+}
+
+#[cfg(test)]
+mod test {
+
+    use super::*;
+
+    #[test]
+    fn test_jpeg_from_stream_item() {
+        let item = as_jpeg_stream_item(b"jpeg data");
+        assert_eq!(jpeg_from_stream_item(&item).unwrap(), "jpeg data");
+        assert!(jpeg_from_stream_item(&Bytes::from_static(b"--frame")).is_none());
+    }
+
+    #[tokio::test]
+    async fn test_spawn_retention() {
+        let (runs_tx, mut runs_rx) = tokio::sync::mpsc::unbounded_channel();
+        let handle = spawn_retention("items", Duration::from_millis(10), move || {
+            runs_tx.send(()).unwrap();
+            Ok(1)
+        });
+
+        // The retention runs right away and then periodically
+        for _ in 0..3 {
+            tokio::time::timeout(Duration::from_secs(1), runs_rx.recv())
+                .await
+                .unwrap()
+                .unwrap();
+        }
+        handle.abort();
+    }
+}
@@ -1,5 +1,8 @@
 use std::{
-    sync::atomic::{AtomicU64, Ordering},
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, Mutex,
+    },
     time::{Duration, Instant},
 };
 
@@ -11,6 +14,7 @@ pub static METER: Meter = Meter::new();
 pub struct Meter {
     raw_frames: AtomicU64,
     infered_frames: AtomicU64,
+    workers: Mutex<Vec<Arc<WorkerMeter>>>,
 }
 
 impl Meter {
@@ -18,9 +22,27 @@ impl Meter {
         Meter {
             raw_frames: AtomicU64::new(0),
             infered_frames: AtomicU64::new(0),
+            workers: Mutex::new(Vec::new()),
         }
     }
 
+    /// Register an inference worker and get the meter it reports to.
+    pub fn register_worker(&self) -> Arc<WorkerMeter> {
+        let worker = Arc::new(WorkerMeter::default());
+        self.workers.lock().unwrap().push(worker.clone());
+        worker
+    }
+
+    /// Get and reset the inferred frames and busy time of each registered worker.
+    pub fn get_reset_workers(&self) -> Vec<(u64, Duration)> {
+        self.workers
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|worker| worker.get_reset())
+            .collect()
+    }
+
     pub fn tick_raw(&self) {
         self.raw_frames.fetch_add(1, Ordering::Relaxed);
     }
@@ -38,6 +60,28 @@ impl Meter {
     }
 }
 
+/// Meter of a single inference worker.
+#[derive(Default)]
+pub struct WorkerMeter {
+    frames: AtomicU64,
+    busy_us: AtomicU64,
+}
+
+impl WorkerMeter {
+    /// Record inferred frames and the time spent on them.
+    pub fn record(&self, frames: u64, busy: Duration) {
+        self.frames.fetch_add(frames, Ordering::Relaxed);
+        self.busy_us
+            .fetch_add(busy.as_micros() as u64, Ordering::Relaxed);
+    }
+
+    pub fn get_reset(&self) -> (u64, Duration) {
+        let frames = self.frames.swap(0, Ordering::Relaxed);
+        let busy_us = self.busy_us.swap(0, Ordering::Relaxed);
+        (frames, Duration::from_micros(busy_us))
+    }
+}
+
 pub fn spawn_meter_logger() -> JoinHandle<()> {
     tokio::spawn(async {
         let mut log_interval = interval(Duration::from_secs(2));
@@ -59,6 +103,13 @@ pub fn spawn_meter_logger() -> JoinHandle<()> {
             if infered_frames > 0 {
                 log::info!("Infered frames per second: {fps_infered:.2}")
             }
+
+            for (index, (frames, busy)) in METER.get_reset_workers().into_iter().enumerate() {
+                if frames > 0 {
+                    let utilization = 100.0 * busy.as_secs_f32() / elapsed;
+                    log::info!("Inference worker {index}: {frames} frames, {utilization:.0}% busy")
+                }
+            }
         }
     })
 }
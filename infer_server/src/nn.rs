@@ -1,18 +1,69 @@
 //! Neural network module with model struct, pre- and post-process functions.
 //!
-use anyhow::Result;
+use std::{
+    path::{Path, PathBuf},
+    str::FromStr,
+    sync::RwLock,
+};
+
+use anyhow::{bail, Context, Result};
 use image::{GenericImageView, Rgb, RgbImage};
-use ndarray::s;
+use ndarray::{s, ArrayViewD, Axis, Ix3};
+use serde::{Deserialize, Serialize};
 use smallvec::SmallVec;
 use tract_onnx::prelude::*;
 
-use crate::utils::download_file;
+use crate::utils::{download_file, sha256_file, verify_sha256};
 
 /// Bounding box defined as `[x_top_left, y_top_left, x_bottom_right, y_bottom_right]`.
 pub type Bbox = [f32; 4];
 
-type NnModel = SimplePlan<TypedFact, Box<dyn TypedOp>, Graph<TypedFact, Box<dyn TypedOp>>>;
-type NnOut = SmallVec<[TValue; 4]>;
+/// Point defined as `[x, y]`.
+pub type Point = [f32; 2];
+
+/// Detected object with its class, score and **relative** coordinates.
+#[derive(Clone, Debug, PartialEq, Serialize)]
+pub struct Detection {
+    pub class_id: usize,
+    pub label: String,
+    pub score: f32,
+    pub bbox: Bbox,
+    /// Landmarks like eyes, nose and mouth corners in **relative** coordinates.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub landmarks: Option<Vec<Point>>,
+    /// Id of the track of the object across frames, set once the track is confirmed.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub track_id: Option<u64>,
+    /// Name of the zone of the stream the detection falls in.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub zone: Option<String>,
+}
+
+impl Detection {
+    pub fn new(class_id: usize, label: impl Into<String>, score: f32, bbox: Bbox) -> Self {
+        Self {
+            class_id,
+            label: label.into(),
+            score,
+            bbox,
+            landmarks: None,
+            track_id: None,
+            zone: None,
+        }
+    }
+
+    pub fn with_landmarks(mut self, landmarks: Option<Vec<Point>>) -> Self {
+        self.landmarks = landmarks;
+        self
+    }
+}
+
+pub(crate) type NnModel =
+    SimplePlan<TypedFact, Box<dyn TypedOp>, Graph<TypedFact, Box<dyn TypedOp>>>;
+pub(crate) type NnOut = SmallVec<[TValue; 4]>;
+
+/// Model shared between the inferer and the endpoints.
+pub type SharedModel = Arc<dyn InferModel + Send + Sync>;
 
 /// Positive additive constant to avoid divide-by-zero.
 const EPS: f32 = 1.0e-7;
@@ -21,11 +72,142 @@ const EPS: f32 = 1.0e-7;
 const ULTRAFACE_LINK_640: &str = "https://github.com/onnx/models/raw/main/vision/body_analysis/ultraface/models/version-RFB-640.onnx";
 const ULTRAFACE_LINK_320: &str = "https://github.com/onnx/models/raw/main/vision/body_analysis/ultraface/models/version-RFB-320.onnx";
 
+/// Label of the single class detected by Ultraface.
+const ULTRAFACE_LABEL: &str = "face";
+
 pub trait InferModel {
-    fn run(&self, input: &RgbImage) -> Result<Vec<(Bbox, f32)>>;
+    fn run(&self, input: &RgbImage) -> Result<Vec<Detection>>;
+    /// Infer several images at once, one by one unless the model supports batches.
+    fn run_batch(&self, inputs: &[RgbImage]) -> Result<Vec<Vec<Detection>>> {
+        inputs.iter().map(|input| self.run(input)).collect()
+    }
+
+    /// Get the current post-processing thresholds.
+    fn config(&self) -> DetectorConfig;
+
+    /// Replace the post-processing thresholds, used from the next inference on.
+    fn set_config(&self, config: DetectorConfig) -> Result<()>;
+}
+
+/// Order of the color channels in the input tensor of a model.
+#[derive(Clone, Copy, Debug, Default, Deserialize, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum ChannelOrder {
+    #[default]
+    Rgb,
+    Bgr,
+}
+
+/// Resize an image and convert it to a normalized `1x3xHxW` input tensor.
+///
+/// Pixel values are scaled to `[0, 1]` before subtracting `mean` and dividing by `std`. Both are
+/// given in RGB order.
+pub(crate) fn image_to_tensor<I>(
+    input: &I,
+    width: u32,
+    height: u32,
+    mean: [f32; 3],
+    std: [f32; 3],
+    channel_order: ChannelOrder,
+) -> Tensor
+where
+    I: GenericImageView<Pixel = Rgb<u8>>,
+{
+    let resized: RgbImage = image::imageops::resize(
+        input,
+        width,
+        height,
+        // TODO: Test different filters
+        image::imageops::FilterType::Triangle,
+    );
+
+    tract_ndarray::Array4::from_shape_fn((1, 3, height as usize, width as usize), |(_, c, y, x)| {
+        let c = match channel_order {
+            ChannelOrder::Rgb => c,
+            ChannelOrder::Bgr => 2 - c,
+        };
+        (resized[(x as _, y as _)][c] as f32 / 255.0 - mean[c]) / std[c]
+    })
+    .into()
+}
+
+/// Stack images to a single `Nx3xHxW` input tensor.
+pub(crate) fn images_to_tensor<I>(
+    inputs: &[I],
+    width: u32,
+    height: u32,
+    mean: [f32; 3],
+    std: [f32; 3],
+    channel_order: ChannelOrder,
+) -> Result<Tensor>
+where
+    I: GenericImageView<Pixel = Rgb<u8>>,
+{
+    let tensors: Vec<_> = inputs
+        .iter()
+        .map(|input| image_to_tensor(input, width, height, mean, std, channel_order))
+        .collect();
+
+    Tensor::stack_tensors(0, &tensors)
+}
+
+/// Load and optimize an ONNX model file for inputs of the given size.
+///
+/// A `batched` model accepts inputs with any batch size `N` instead of only `1`.
+pub(crate) fn load_model(
+    model_file_path: &Path,
+    width: u32,
+    height: u32,
+    batched: bool,
+) -> Result<NnModel> {
+    let model = tract_onnx::onnx().model_for_path(model_file_path)?;
+    let batch_size = match batched {
+        true => model.symbol_table.sym("N").to_dim(),
+        false => 1.to_dim(),
+    };
+    let input_fact = InferenceFact::dt_shape(
+        f32::datum_type(),
+        tvec!(
+            batch_size,
+            3.to_dim(),
+            (height as usize).to_dim(),
+            (width as usize).to_dim()
+        ),
+    );
+    let model = model
+        .with_input_fact(0, input_fact)?
+        .into_optimized()
+        .with_context(|| match batched {
+            true => "failed to optimize model with dynamic batch size".to_owned(),
+            false => "failed to optimize model".to_owned(),
+        })?
+        .into_runnable()?;
+
+    Ok(model)
+}
+
+/// Get the outputs of a single item of a batch, keeping the batch axis.
+pub(crate) fn batch_item<'a>(
+    raw_nn_out: &'a NnOut,
+    output: usize,
+    index: usize,
+) -> Result<ArrayViewD<'a, f32>> {
+    let view = raw_nn_out
+        .get(output)
+        .with_context(|| format!("model has no output {output}"))?
+        .to_array_view::<f32>()?;
+    if view.ndim() == 0 || view.shape()[0] <= index {
+        bail!(
+            "output {output} of shape {:?} has no batch item {index}",
+            view.shape()
+        );
+    }
+
+    Ok(view.index_axis_move(Axis(0), index).insert_axis(Axis(0)))
 }
 
 /// Supported variants of the Ultraface model.
+#[derive(Clone, Copy, Debug, PartialEq)]
 pub enum UltrafaceVariant {
     W640H480,
     W320H240,
@@ -41,180 +223,346 @@ impl UltrafaceVariant {
     }
 }
 
+impl FromStr for UltrafaceVariant {
+    type Err = String;
+
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        match s {
+            "640" | "W640H480" | "w640h480" => Ok(UltrafaceVariant::W640H480),
+            "320" | "W320H240" | "w320h240" => Ok(UltrafaceVariant::W320H240),
+            _ => Err(format!(
+                "unknown Ultraface variant {s}, expected one of 640, 320"
+            )),
+        }
+    }
+}
+
+/// Check that a post-processing threshold is a value in the range `[0, 1]`.
+pub fn validate_threshold(name: &str, value: f32) -> Result<f32> {
+    if !(0.0..=1.0).contains(&value) {
+        bail!("{name} has to be in the range [0, 1], got {value}");
+    }
+
+    Ok(value)
+}
+
+/// Options for locating, verifying and loading model files.
+#[derive(Clone, Debug, Default)]
+pub struct ModelOptions {
+    /// Load the model from this file instead of the cache directory.
+    pub model_path: Option<PathBuf>,
+    /// Expected SHA-256 checksum of the model file as hex string.
+    pub sha256: Option<String>,
+    /// Never download models, fail if the model file is not available locally.
+    pub offline: bool,
+    /// Load the model with a dynamic batch size to infer several frames at once.
+    pub batched: bool,
+}
+
+/// Post-processing thresholds of a detector.
+#[derive(Clone, Copy, Debug, Deserialize, PartialEq, Serialize)]
+pub struct DetectorConfig {
+    /// Maximum IoU of two detections before the less confident one is suppressed.
+    pub max_iou: f32,
+    /// Minimum confidence of a detection to be selected.
+    pub min_confidence: f32,
+}
+
+impl DetectorConfig {
+    pub fn new(max_iou: f32, min_confidence: f32) -> Result<Self> {
+        Ok(Self {
+            max_iou: validate_threshold("max_iou", max_iou)?,
+            min_confidence: validate_threshold("min_confidence", min_confidence)?,
+        })
+    }
+
+    /// Check that all thresholds are in their valid ranges.
+    pub fn validate(self) -> Result<Self> {
+        Self::new(self.max_iou, self.min_confidence)
+    }
+}
+
 /// Loaded Ultraface model, ready for inference with post-processing thresholds.
+///
+/// The thresholds can be changed while the model is in use and apply from the next frame on.
 pub struct UltrafaceModel {
     model: NnModel,
     width: u32,
     height: u32,
-    max_iou: f32,
-    min_confidence: f32,
+    batched: bool,
+    config: RwLock<DetectorConfig>,
 }
 
 impl UltrafaceModel {
     /// Load and prepare an Ultraface model for inference.
     pub async fn new(variant: UltrafaceVariant, max_iou: f32, min_confidence: f32) -> Result<Self> {
+        Self::with_options(variant, max_iou, min_confidence, &ModelOptions::default()).await
+    }
+
+    /// Load and prepare an Ultraface model for inference, located and verified by `options`.
+    pub async fn with_options(
+        variant: UltrafaceVariant,
+        max_iou: f32,
+        min_confidence: f32,
+        options: &ModelOptions,
+    ) -> Result<Self> {
+        let config = DetectorConfig::new(max_iou, min_confidence)?;
+
         let (width, height) = variant.width_height();
-        let model = Self::get_model(&variant).await?;
+        let model = Self::get_model(&variant, options).await?;
         println!("Initialized Ultraface model");
 
         Ok(Self {
             model,
             width,
             height,
-            max_iou,
-            min_confidence,
+            batched: options.batched,
+            config: RwLock::new(config),
         })
     }
 
-    /// Pre-process an image to be used as inference input.
-    fn preproc<I>(&self, input: &I) -> TValue
+    /// Pre-process a batch of images to be used as inference input.
+    fn preproc<I>(&self, inputs: &[I]) -> Result<TValue>
     where
         I: GenericImageView<Pixel = Rgb<u8>>,
     {
-        let resized: RgbImage = image::imageops::resize(
-            input,
+        // Note: Mean/std are from MobileNet, not from Ultraface, but work well
+        let tensor = images_to_tensor(
+            inputs,
             self.width,
             self.height,
-            // TODO: Test different filters
-            image::imageops::FilterType::Triangle,
-        );
+            [0.485, 0.456, 0.406],
+            [0.229, 0.224, 0.225],
+            ChannelOrder::Rgb,
+        )?;
 
-        let tensor: Tensor = tract_ndarray::Array4::from_shape_fn(
-            (1, 3, self.height as usize, self.width as usize),
-            |(_, c, y, x)| {
-                // Note: Mean/std are from MobileNet, not from Ultraface, but work well
-                let mean = [0.485, 0.456, 0.406][c];
-                let std = [0.229, 0.224, 0.225][c];
-                (resized[(x as _, y as _)][c] as f32 / 255.0 - mean) / std
-            },
-        )
-        .into();
-
-        TValue::from_const(Arc::new(tensor))
+        Ok(TValue::from_const(Arc::new(tensor)))
     }
 
-    /// Post-process raw inference output to selected bounding boxes.
+    /// Post-process raw inference output of the batch item `index` to selected bounding boxes.
     ///
     /// The raw inference output `raw_nn_out` consist of two tensors:
-    /// - `raw_nn_out[0]` is a `1xKx2` tensor of bounding box confidences. The confidences for
+    /// - `raw_nn_out[0]` is a `NxKx2` tensor of bounding box confidences. The confidences for
     ///   having a face in a bounding box are given in the second column at `[:,:,1]`.
-    /// - `raw_nn_out[1]` is a `1xKx4` tensor of bounding box candidate border points. Every
+    /// - `raw_nn_out[1]` is a `NxKx4` tensor of bounding box candidate border points. Every
     ///   candidate bounding box consists of the **relative** coordinates
     ///   `[x_top_left, y_top_left, x_bottom_right, y_bottom_right]`. They can be multiplied with
     ///   the `width` and `height` of the original image to obtain the bounding box coordinates for
     ///   the real frame.
     ///
-    /// The output is a vector of bounding boxes with confidence scores in descending order of
-    /// certainty. The bounding boxes are defined by their **relative** coordinates.
-    fn postproc(&self, raw_nn_out: NnOut) -> Result<Vec<(Bbox, f32)>> {
+    /// The output is a vector of face detections in descending order of certainty. The bounding
+    /// boxes are defined by their **relative** coordinates.
+    fn postproc(&self, raw_nn_out: &NnOut, index: usize) -> Result<Vec<Detection>> {
+        // Use one consistent set of thresholds for the whole frame
+        let config = self.config();
+
         // Extract confidences
-        let confidences = raw_nn_out[0].to_array_view::<f32>()?;
+        let confidences = batch_item(raw_nn_out, 0, index)?;
         let confidences = confidences.slice(s![0, .., 1]);
 
         // Extract relative coordinates of bounding boxes
-        let bboxes = raw_nn_out[1].to_array_view::<f32>()?;
+        let bboxes = batch_item(raw_nn_out, 1, index)?.into_dimensionality::<Ix3>()?;
         let bboxes = bboxes
-            .as_slice()
-            .unwrap()
-            .chunks(4)
-            .map(|x| Bbox::try_from(x).unwrap());
+            .index_axis(Axis(0), 0)
+            .outer_iter()
+            .map(|x| [x[0], x[1], x[2], x[3]])
+            .collect::<Vec<Bbox>>();
 
         // Fuse bounding boxes with confidence scores
         // Filter out bounding boxes with a confidence score below the threshold
-        let mut bboxes_with_confidences: Vec<_> = bboxes
+        let mut candidates: Vec<_> = bboxes
+            .into_iter()
             .zip(confidences.iter())
             .filter_map(|(bbox, confidence)| match confidence {
-                x if *x > self.min_confidence => Some((bbox, confidence)),
+                x if *x > config.min_confidence => {
+                    Some(Detection::new(0, ULTRAFACE_LABEL, *confidence, bbox))
+                }
                 _ => None,
             })
             .collect();
 
-        // Sort pairs of bounding boxes with confidence scores by **ascending** confidences to allow
-        // cheap removal of the top candidates from the back
-        bboxes_with_confidences.sort_by(|a, b| a.1.partial_cmp(b.1).unwrap());
+        // Sort candidates by **ascending** confidences to allow cheap removal of the top
+        // candidates from the back
+        candidates.sort_by(|a, b| a.score.partial_cmp(&b.score).unwrap());
 
-        // Run non-maximum suppression on the sorted vector of bounding boxes with confidences
-        let selected_bboxes = non_maximum_suppression(bboxes_with_confidences, self.max_iou);
+        // Run non-maximum suppression on the sorted vector of candidates
+        let selected = non_maximum_suppression(candidates, config.max_iou);
 
-        Ok(selected_bboxes)
+        Ok(selected)
     }
 
     /// Get model by looking it up in the cache or downloading it if not found.
-    async fn get_model(variant: &UltrafaceVariant) -> Result<NnModel> {
+    async fn get_model(variant: &UltrafaceVariant, options: &ModelOptions) -> Result<NnModel> {
         let (model_name, download_link) = match variant {
             UltrafaceVariant::W640H480 => ("ultraface-RFB-640.onnx", ULTRAFACE_LINK_640),
             UltrafaceVariant::W320H240 => ("ultraface-RFB-320.onnx", ULTRAFACE_LINK_320),
         };
 
-        // Create cache directory if it does not exist
         let model_file_dir = dirs::cache_dir().expect("cache dir").join("infercam_onnx");
-        if !model_file_dir.is_dir() {
-            std::fs::create_dir_all(&model_file_dir)?;
-        }
+        let model_file_path =
+            get_model_file(&model_file_dir, model_name, download_link, options).await?;
 
-        // Download model file if it is not found
-        let model_file_path = model_file_dir.join(model_name);
+        // Load and optimize model file
+        let (width, height) = variant.width_height();
+        load_model(&model_file_path, width, height, options.batched)
+    }
+}
+
+/// Get the path to a verified model file.
+///
+/// A model file given in `options` is used as-is. Otherwise, the model is looked up in
+/// `model_file_dir` and downloaded if it is not found there, unless running offline.
+///
+/// Every model file is checked against the SHA-256 checksum from `options` if given. Without an
+/// explicit checksum, the file is checked against the checksum recorded in a `.sha256` file next
+/// to it. For files in the cache directory, this record is created on first use, so that later
+/// corruption of the cached file is detected.
+async fn get_model_file(
+    model_file_dir: &Path,
+    model_name: &str,
+    download_link: &str,
+    options: &ModelOptions,
+) -> Result<PathBuf> {
+    if let Some(model_file_path) = &options.model_path {
         if !model_file_path.is_file() {
-            let client = reqwest::Client::new();
-            println!("Downloading Ultraface model...");
-            download_file(&client, download_link, &model_file_path).await?;
-            println!("Download complete");
+            bail!("model file {} not found", model_file_path.display());
         }
+        verify_model_file(model_file_path, options.sha256.as_deref(), false)?;
 
-        // Load and optimize model file
-        let (width, height) = variant.width_height();
-        let input_fact =
-            InferenceFact::dt_shape(f32::datum_type(), tvec!(1, 3, height as i32, width as i32));
-        let model = tract_onnx::onnx()
-            .model_for_path(model_file_path)?
-            .with_input_fact(0, input_fact)?
-            .into_optimized()?
-            .into_runnable()?;
-
-        Ok(model)
+        return Ok(model_file_path.clone());
+    }
+
+    // Create cache directory if it does not exist
+    if !model_file_dir.is_dir() {
+        std::fs::create_dir_all(model_file_dir)?;
+    }
+
+    // Download model file if it is not found
+    let model_file_path = model_file_dir.join(model_name);
+    if !model_file_path.is_file() {
+        if options.offline {
+            bail!(
+                "model file {} not found and downloads are disabled in offline mode, \
+                 provide the model with --model-path",
+                model_file_path.display()
+            );
+        }
+
+        let client = reqwest::Client::new();
+        println!("Downloading Ultraface model...");
+        download_file(&client, download_link, &model_file_path).await?;
+        println!("Download complete");
+
+        // Do not keep a downloaded file which does not match the expected checksum
+        if let Err(err) = verify_model_file(&model_file_path, options.sha256.as_deref(), true) {
+            std::fs::remove_file(&model_file_path).ok();
+            return Err(err);
+        }
+    } else {
+        verify_model_file(&model_file_path, options.sha256.as_deref(), true)?;
     }
+
+    Ok(model_file_path)
+}
+
+/// Verify a model file against an expected checksum or the recorded one next to the file.
+pub(crate) fn verify_model_file(
+    model_file_path: &Path,
+    sha256: Option<&str>,
+    record_checksum: bool,
+) -> Result<()> {
+    let mut checksum_file_path = model_file_path.as_os_str().to_owned();
+    checksum_file_path.push(".sha256");
+    let checksum_file_path = PathBuf::from(checksum_file_path);
+
+    match sha256 {
+        Some(expected) => verify_sha256(model_file_path, expected)?,
+        None if checksum_file_path.is_file() => {
+            let recorded = std::fs::read_to_string(&checksum_file_path)?;
+            verify_sha256(model_file_path, &recorded).map_err(|err| {
+                err.context(format!(
+                    "model file does not match {}, remove both files to download it again",
+                    checksum_file_path.display()
+                ))
+            })?;
+        }
+        None => (),
+    }
+
+    if record_checksum && !checksum_file_path.is_file() {
+        std::fs::write(&checksum_file_path, sha256_file(model_file_path)?)?;
+    }
+
+    Ok(())
 }
 
 impl InferModel for UltrafaceModel {
-    fn run(&self, input: &RgbImage) -> Result<Vec<(Bbox, f32)>> {
-        let valid_input = tvec!(self.preproc(input));
+    fn run(&self, input: &RgbImage) -> Result<Vec<Detection>> {
+        let valid_input = tvec!(self.preproc(std::slice::from_ref(input))?);
         let raw_nn_out = self.model.run(valid_input)?;
-        let selected_bboxes = self.postproc(raw_nn_out)?;
+        let selected_bboxes = self.postproc(&raw_nn_out, 0)?;
 
         Ok(selected_bboxes)
     }
+
+    fn run_batch(&self, inputs: &[RgbImage]) -> Result<Vec<Vec<Detection>>> {
+        if !self.batched || inputs.len() < 2 {
+            return inputs.iter().map(|input| self.run(input)).collect();
+        }
+
+        let valid_input = tvec!(self.preproc(inputs)?);
+        let raw_nn_out = self.model.run(valid_input)?;
+
+        (0..inputs.len())
+            .map(|index| self.postproc(&raw_nn_out, index))
+            .collect()
+    }
+
+    fn config(&self) -> DetectorConfig {
+        *self.config.read().unwrap()
+    }
+
+    fn set_config(&self, config: DetectorConfig) -> Result<()> {
+        let config = config.validate()?;
+        *self.config.write().unwrap() = config;
+
+        Ok(())
+    }
 }
 
-/// Run non-maximum-suppression on candidate bounding boxes.
+/// Run non-maximum-suppression on candidate detections.
 ///
-/// The pairs of bounding boxes with confidences have to be sorted in **ascending** order of
-/// confidence because we want to `pop()` the most confident elements from the back.
+/// The candidates have to be sorted in **ascending** order of score because we want to `pop()`
+/// the most confident elements from the back.
 ///
-/// Start with the most confident bounding box and iterate over all other bounding boxes in the
-/// order of decreasing confidence. Grow the vector of selected bounding boxes by adding only those
-/// candidates which do not have a IoU scores above `max_iou` with already chosen bounding boxes.
-/// This iterates over all bounding boxes in `sorted_bboxes_with_confidences`. Any candidates with
-/// scores generally too low to be considered should be filtered out before.
-fn non_maximum_suppression(
-    mut sorted_bboxes_with_confidences: Vec<(Bbox, &f32)>,
+/// Start with the most confident candidate and iterate over all other candidates in the order of
+/// decreasing confidence. Grow the vector of selected detections by adding only those candidates
+/// which do not have a IoU scores above `max_iou` with already chosen detections of the same
+/// class. This iterates over all candidates in `sorted_candidates`. Any candidates with scores
+/// generally too low to be considered should be filtered out before.
+pub(crate) fn non_maximum_suppression(
+    mut sorted_candidates: Vec<Detection>,
     max_iou: f32,
-) -> Vec<(Bbox, f32)> {
-    let mut selected = Vec::with_capacity(10);
+) -> Vec<Detection> {
+    let mut selected: Vec<Detection> = Vec::with_capacity(10);
     'candidates: loop {
-        // Get next most confident bbox from the back of ascending-sorted vector.
-        // All boxes fulfill the minimum confidence criterium.
-        match sorted_bboxes_with_confidences.pop() {
-            Some((bbox, confidence)) => {
-                // Check for overlap with any of the selected bboxes
-                for (selected_bbox, _) in selected.iter() {
-                    match iou(&bbox, selected_bbox) {
+        // Get next most confident candidate from the back of ascending-sorted vector.
+        // All candidates fulfill the minimum confidence criterium.
+        match sorted_candidates.pop() {
+            Some(candidate) => {
+                // Check for overlap with any of the selected detections of the same class
+                for selected_detection in selected.iter() {
+                    if selected_detection.class_id != candidate.class_id {
+                        continue;
+                    }
+                    match iou(&candidate.bbox, &selected_detection.bbox) {
                         x if x > max_iou => continue 'candidates,
                         _ => (),
                     }
                 }
 
-                // bbox has no large overlap with any of the selected ones, add it
-                selected.push((bbox, *confidence))
+                // Candidate has no large overlap with any of the selected ones, add it
+                selected.push(candidate)
             }
             None => break 'candidates,
         }
@@ -224,7 +572,7 @@ fn non_maximum_suppression(
 }
 
 /// Calculate the intersection-over-union metric for two bounding boxes.
-fn iou(bbox_a: &Bbox, bbox_b: &Bbox) -> f32 {
+pub(crate) fn iou(bbox_a: &Bbox, bbox_b: &Bbox) -> f32 {
     // Calculate corner points of overlap box
     // If the boxes do not overlap, the corner-points will be ill defined, i.e. the top left
     // corner point will be below and to the right of the bottom right corner point. In this case,
@@ -258,3 +606,118 @@ fn bbox_area(bbox: &Bbox) -> f32 {
 
     width * height
 }
+
+#[cfg(test)]
+mod test {
+
+    use super::*;
+
+    #[test]
+    fn test_non_maximum_suppression_per_class() {
+        let candidates = vec![
+            Detection::new(0, "face", 0.6, [0.1, 0.1, 0.5, 0.5]),
+            Detection::new(1, "person", 0.7, [0.1, 0.1, 0.5, 0.5]),
+            Detection::new(0, "face", 0.8, [0.6, 0.6, 0.9, 0.9]),
+            Detection::new(0, "face", 0.9, [0.1, 0.1, 0.5, 0.6]),
+        ];
+
+        let selected = non_maximum_suppression(candidates, 0.5);
+        let scores: Vec<_> = selected.iter().map(|d| d.score).collect();
+
+        // The overlapping face with 0.6 is suppressed, the person of another class is not
+        assert_eq!(scores, vec![0.9, 0.8, 0.7]);
+    }
+
+    #[test]
+    fn test_ultraface_variant_from_str() {
+        assert_eq!("640".parse(), Ok(UltrafaceVariant::W640H480));
+        assert_eq!("W320H240".parse(), Ok(UltrafaceVariant::W320H240));
+        assert!("480".parse::<UltrafaceVariant>().is_err());
+    }
+
+    #[test]
+    fn test_validate_threshold() {
+        assert_eq!(validate_threshold("max_iou", 0.5).unwrap(), 0.5);
+        assert!(validate_threshold("max_iou", -0.1).is_err());
+        assert!(validate_threshold("min_confidence", 1.5).is_err());
+        assert!(validate_threshold("min_confidence", f32::NAN).is_err());
+    }
+
+    #[tokio::test]
+    async fn test_get_model_file_offline() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        let options = ModelOptions {
+            offline: true,
+            ..Default::default()
+        };
+
+        // Missing model file must not be downloaded in offline mode
+        let err = get_model_file(dir.path(), "model.onnx", "http://127.0.0.1:9", &options)
+            .await
+            .unwrap_err();
+        assert!(err.to_string().contains("offline mode"));
+
+        // Cached model file is used and its checksum recorded
+        let model_file_path = dir.path().join("model.onnx");
+        std::fs::write(&model_file_path, b"model")?;
+        let path = get_model_file(dir.path(), "model.onnx", "http://127.0.0.1:9", &options).await?;
+        assert_eq!(path, model_file_path);
+        assert!(dir.path().join("model.onnx.sha256").is_file());
+
+        // Corrupted cached model file is detected
+        std::fs::write(&model_file_path, b"corrupted")?;
+        assert!(
+            get_model_file(dir.path(), "model.onnx", "http://127.0.0.1:9", &options)
+                .await
+                .is_err()
+        );
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_get_model_file_from_path() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        let model_file_path = dir.path().join("custom.onnx");
+        std::fs::write(&model_file_path, b"abc")?;
+
+        let mut options = ModelOptions {
+            model_path: Some(model_file_path.clone()),
+            sha256: Some(
+                "ba7816bf8f01cfea414140de5dae2223b00361a396177a9cb410ff61f20015ad".to_owned(),
+            ),
+            offline: true,
+            batched: false,
+        };
+        let cache_dir = dir.path().join("cache");
+        let path = get_model_file(&cache_dir, "model.onnx", "", &options).await?;
+        assert_eq!(path, model_file_path);
+
+        options.sha256 = Some("0".repeat(64));
+        assert!(get_model_file(&cache_dir, "model.onnx", "", &options)
+            .await
+            .is_err());
+
+        options.model_path = Some(dir.path().join("missing.onnx"));
+        assert!(get_model_file(&cache_dir, "model.onnx", "", &options)
+            .await
+            .is_err());
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_detector_config_validate() {
+        let config = DetectorConfig {
+            max_iou: 0.3,
+            min_confidence: 0.7,
+        };
+        assert_eq!(config.validate().unwrap(), config);
+
+        let config = DetectorConfig {
+            max_iou: 0.3,
+            min_confidence: 2.0,
+        };
+        assert!(config.validate().is_err());
+    }
+}
@@ -0,0 +1,530 @@
+//! Continuous recording of streams into segments and their playback.
+//!
+//! Every segment of a stream consists of `<dir>/<stream>/<start_ms>.mjpeg` with the concatenated
+//! JPEG frames and `<start_ms>.idx` with a line `<timestamp_ms> <offset> <length>` per frame.
+//! Segments older than the maximum age and the oldest ones beyond the maximum total size are
+//! deleted periodically.
+use std::{
+    fs::File,
+    io::{BufRead, BufReader, BufWriter, SeekFrom, Write},
+    path::{Path, PathBuf},
+    sync::Arc,
+    time::Duration,
+};
+
+use anyhow::{bail, Context, Result};
+use bytes::Bytes;
+use futures::{stream, Stream, StreamExt};
+use tokio::{
+    io::{AsyncReadExt, AsyncSeekExt},
+    sync::broadcast::error::RecvError,
+    task::JoinHandle,
+    time::Instant,
+};
+
+use crate::{
+    clips::stream_dir_name, jpeg_from_stream_item, router::timestamp_ms, BroadcastReceiver,
+};
+
+/// Extension of the files with the frames of a segment.
+const DATA_EXTENSION: &str = "mjpeg";
+/// Extension of the files with the frame index of a segment.
+const INDEX_EXTENSION: &str = "idx";
+/// Longest time range which can be played back at once.
+pub const MAX_PLAYBACK_MS: u64 = 60 * 60 * 1000;
+/// Interval of deleting segments beyond the retention.
+const RETENTION_INTERVAL: Duration = Duration::from_secs(60);
+
+/// Settings of the continuous recording, shared by all recorded streams.
+#[derive(Clone, Debug, PartialEq)]
+pub struct RecordingConfig {
+    /// Directory with a subdirectory of segments per stream.
+    pub dir: PathBuf,
+    /// Duration of a segment in milliseconds before the next one is started.
+    pub segment_ms: u64,
+    /// Segments older than this are deleted.
+    pub max_age_ms: u64,
+    /// The oldest segments are deleted when all segments together get larger than this.
+    pub max_bytes: u64,
+}
+
+/// Recorded frame in a segment.
+#[derive(Clone, Debug, PartialEq)]
+pub struct RecordedFrame {
+    /// Time of receiving the frame in milliseconds since the UNIX epoch.
+    pub timestamp_ms: u64,
+    /// Data file of the segment.
+    pub path: Arc<PathBuf>,
+    pub offset: u64,
+    pub len: u64,
+}
+
+/// Segment which is currently written.
+struct Segment {
+    start_ms: u64,
+    data: BufWriter<File>,
+    index: BufWriter<File>,
+    offset: u64,
+}
+
+/// Recorder of the segments of a single stream.
+pub struct SegmentRecorder {
+    config: RecordingConfig,
+    dir: PathBuf,
+    segment: Option<Segment>,
+}
+
+impl SegmentRecorder {
+    pub fn new(stream: &str, config: RecordingConfig) -> Self {
+        Self {
+            dir: config.dir.join(stream_dir_name(stream)),
+            config,
+            segment: None,
+        }
+    }
+
+    /// Append a frame to the current segment, starting a new one when it is full.
+    pub fn push_frame(&mut self, timestamp_ms: u64, jpeg: &[u8]) -> Result<()> {
+        let segment = match self.segment.take() {
+            Some(segment)
+                if timestamp_ms.saturating_sub(segment.start_ms) < self.config.segment_ms =>
+            {
+                segment
+            }
+            _ => self.start_segment(timestamp_ms)?,
+        };
+        let segment = self.segment.insert(segment);
+
+        segment.data.write_all(jpeg)?;
+        segment.data.flush()?;
+        writeln!(
+            segment.index,
+            "{timestamp_ms} {} {}",
+            segment.offset,
+            jpeg.len()
+        )?;
+        segment.index.flush()?;
+        segment.offset += jpeg.len() as u64;
+
+        Ok(())
+    }
+
+    fn start_segment(&self, start_ms: u64) -> Result<Segment> {
+        std::fs::create_dir_all(&self.dir)
+            .with_context(|| format!("failed to create {}", self.dir.display()))?;
+        let create = |extension: &str| -> Result<BufWriter<File>> {
+            let path = self.dir.join(format!("{start_ms}.{extension}"));
+            let file = File::create(&path)
+                .with_context(|| format!("failed to create {}", path.display()))?;
+            Ok(BufWriter::new(file))
+        };
+
+        Ok(Segment {
+            start_ms,
+            data: create(DATA_EXTENSION)?,
+            index: create(INDEX_EXTENSION)?,
+            offset: 0,
+        })
+    }
+}
+
+/// Start times of the recorded segments of a stream, sorted.
+fn list_segments(stream_dir: &Path) -> Result<Vec<u64>> {
+    let mut segments = Vec::new();
+    if !stream_dir.exists() {
+        return Ok(segments);
+    }
+
+    for entry in std::fs::read_dir(stream_dir)? {
+        let path = entry?.path();
+        if path.extension().and_then(|ext| ext.to_str()) != Some(INDEX_EXTENSION) {
+            continue;
+        }
+        if let Some(start_ms) = path
+            .file_stem()
+            .and_then(|stem| stem.to_str())
+            .and_then(|stem| stem.parse().ok())
+        {
+            segments.push(start_ms);
+        }
+    }
+
+    segments.sort_unstable();
+    Ok(segments)
+}
+
+/// Check that a playback range is ordered and not longer than [`MAX_PLAYBACK_MS`].
+pub fn validate_range(from_ms: u64, to_ms: u64) -> Result<()> {
+    if from_ms > to_ms {
+        bail!("from has to be before to, got {from_ms} > {to_ms}");
+    }
+    if to_ms - from_ms > MAX_PLAYBACK_MS {
+        bail!(
+            "playback ranges are limited to {MAX_PLAYBACK_MS} ms, got {}",
+            to_ms - from_ms
+        );
+    }
+
+    Ok(())
+}
+
+/// Find the recorded frames of a stream from `from_ms` to `to_ms` inclusively.
+///
+/// Reads the index files synchronously, use [`validate_range`] to limit their number.
+pub fn find_frames(
+    config: &RecordingConfig,
+    stream: &str,
+    from_ms: u64,
+    to_ms: u64,
+) -> Result<Vec<RecordedFrame>> {
+    let stream_dir = config.dir.join(stream_dir_name(stream));
+    let segments = list_segments(&stream_dir)?;
+
+    let mut frames = Vec::new();
+    for (i, start_ms) in segments.iter().enumerate() {
+        // Skip segments which end before the range or start after it
+        let next_start_ms = segments.get(i + 1).copied().unwrap_or(u64::MAX);
+        if next_start_ms <= from_ms || *start_ms > to_ms {
+            continue;
+        }
+
+        let path = Arc::new(stream_dir.join(format!("{start_ms}.{DATA_EXTENSION}")));
+        let index_path = stream_dir.join(format!("{start_ms}.{INDEX_EXTENSION}"));
+        let index = BufReader::new(File::open(&index_path)?);
+        for line in index.lines() {
+            let line = line?;
+            // The last line of a segment which is still written may be incomplete
+            let Some(frame) = parse_index_line(&line, &path) else {
+                continue;
+            };
+            if (from_ms..=to_ms).contains(&frame.timestamp_ms) {
+                frames.push(frame);
+            }
+        }
+    }
+
+    Ok(frames)
+}
+
+fn parse_index_line(line: &str, path: &Arc<PathBuf>) -> Option<RecordedFrame> {
+    let mut fields = line.split(' ').map(|field| field.parse::<u64>());
+    let (Some(Ok(timestamp_ms)), Some(Ok(offset)), Some(Ok(len)), None) =
+        (fields.next(), fields.next(), fields.next(), fields.next())
+    else {
+        return None;
+    };
+
+    Some(RecordedFrame {
+        timestamp_ms,
+        path: path.clone(),
+        offset,
+        len,
+    })
+}
+
+/// Slowest supported playback speed.
+pub const MIN_SPEED: f32 = 0.1;
+/// Fastest supported playback speed.
+pub const MAX_SPEED: f32 = 100.0;
+
+/// Check that the playback speed is a factor in `[MIN_SPEED, MAX_SPEED]`.
+pub fn validate_speed(speed: f32) -> Result<f32> {
+    if !(MIN_SPEED..=MAX_SPEED).contains(&speed) {
+        bail!("speed has to be in [{MIN_SPEED}, {MAX_SPEED}], got {speed}");
+    }
+    Ok(speed)
+}
+
+/// Stream the JPEG data of the frames, paced by their timestamps divided by `speed`.
+pub fn play_frames(
+    frames: Vec<RecordedFrame>,
+    speed: f32,
+) -> impl Stream<Item = std::io::Result<Bytes>> {
+    let first_ms = frames.first().map_or(0, |frame| frame.timestamp_ms);
+    let start = Instant::now();
+
+    stream::iter(frames).then(move |frame| async move {
+        let elapsed_ms = frame.timestamp_ms.saturating_sub(first_ms) as f64 / speed as f64;
+        let deadline = Duration::try_from_secs_f64(elapsed_ms / 1000.0)
+            .ok()
+            .and_then(|elapsed| start.checked_add(elapsed))
+            .ok_or_else(|| std::io::Error::other("playback time out of range"))?;
+        tokio::time::sleep_until(deadline).await;
+
+        let mut file = tokio::fs::File::open(frame.path.as_ref()).await?;
+        file.seek(SeekFrom::Start(frame.offset)).await?;
+        let mut data = vec![0; frame.len as usize];
+        file.read_exact(&mut data).await?;
+        Ok(Bytes::from(data))
+    })
+}
+
+/// Recorded segment of a stream.
+#[derive(Debug)]
+struct SegmentInfo {
+    stream_dir: PathBuf,
+    start_ms: u64,
+    bytes: u64,
+    /// Whether this is the latest segment of its stream, which may still be written.
+    latest: bool,
+}
+
+impl SegmentInfo {
+    fn path(&self, extension: &str) -> PathBuf {
+        self.stream_dir
+            .join(format!("{}.{extension}", self.start_ms))
+    }
+}
+
+/// Delete segments older than the maximum age and the oldest ones beyond the maximum total size.
+///
+/// The latest segment of every stream is kept since it may still be written. Returns the number
+/// of deleted segments.
+pub fn enforce_retention(config: &RecordingConfig, now_ms: u64) -> Result<usize> {
+    let mut segments = Vec::new();
+    if config.dir.exists() {
+        for stream_dir in std::fs::read_dir(&config.dir)? {
+            let stream_dir = stream_dir?.path();
+            if !stream_dir.is_dir() {
+                continue;
+            }
+
+            let starts = list_segments(&stream_dir)?;
+            for (i, start_ms) in starts.iter().enumerate() {
+                let mut segment = SegmentInfo {
+                    stream_dir: stream_dir.clone(),
+                    start_ms: *start_ms,
+                    bytes: 0,
+                    latest: i + 1 == starts.len(),
+                };
+                for extension in [DATA_EXTENSION, INDEX_EXTENSION] {
+                    segment.bytes += std::fs::metadata(segment.path(extension))
+                        .map(|metadata| metadata.len())
+                        .unwrap_or_default();
+                }
+                segments.push(segment);
+            }
+        }
+    }
+    segments.sort_by_key(|segment| segment.start_ms);
+
+    let mut total_bytes: u64 = segments.iter().map(|segment| segment.bytes).sum();
+    let oldest_ms = now_ms.saturating_sub(config.max_age_ms);
+    let mut deleted = 0;
+    for segment in segments.iter().filter(|segment| !segment.latest) {
+        // Frames of a segment are younger than the start of the next segment
+        let end_ms = segment.start_ms.saturating_add(config.segment_ms);
+        if end_ms >= oldest_ms && total_bytes <= config.max_bytes {
+            break;
+        }
+
+        for extension in [INDEX_EXTENSION, DATA_EXTENSION] {
+            let path = segment.path(extension);
+            if let Err(err) = std::fs::remove_file(&path) {
+                if err.kind() != std::io::ErrorKind::NotFound {
+                    return Err(err)
+                        .with_context(|| format!("failed to delete {}", path.display()));
+                }
+            }
+        }
+        total_bytes -= segment.bytes;
+        deleted += 1;
+    }
+
+    Ok(deleted)
+}
+
+/// Enforce the retention of the recordings at startup and then periodically.
+pub fn spawn_retention(config: RecordingConfig) -> JoinHandle<()> {
+    crate::spawn_retention("recorded segments", RETENTION_INTERVAL, move || {
+        enforce_retention(&config, timestamp_ms())
+    })
+}
+
+/// Record every frame of a stream received on `frames_rx`, raw or anonymized ones.
+///
+/// Runs on a blocking thread which appends each frame to the current segment and its index.
+pub fn spawn_recorder(
+    name: String,
+    config: RecordingConfig,
+    mut frames_rx: BroadcastReceiver,
+) -> JoinHandle<()> {
+    tokio::task::spawn_blocking(move || {
+        let mut recorder = SegmentRecorder::new(&name, config);
+
+        loop {
+            let item = match futures::executor::block_on(frames_rx.recv()) {
+                Ok(item) => item,
+                Err(RecvError::Lagged(skipped)) => {
+                    log::warn!("Recording of {name} skipped {skipped} frames");
+                    continue;
+                }
+                Err(RecvError::Closed) => break,
+            };
+
+            if let Some(jpeg) = jpeg_from_stream_item(&item) {
+                if let Err(err) = recorder.push_frame(timestamp_ms(), &jpeg) {
+                    log::warn!("Failed to record frame of {name}: {err:#}");
+                }
+            }
+        }
+    })
+}
+
+#[cfg(test)]
+mod test {
+
+    use super::*;
+
+    fn config(dir: &Path) -> RecordingConfig {
+        RecordingConfig {
+            dir: dir.to_owned(),
+            segment_ms: 1000,
+            max_age_ms: 10_000,
+            max_bytes: 1024,
+        }
+    }
+
+    /// Record frames every 250 ms from 0 to 2750 ms, in three segments.
+    fn record(config: &RecordingConfig) -> Result<()> {
+        let mut recorder = SegmentRecorder::new("hallway", config.clone());
+        for timestamp_ms in (0..3000).step_by(250) {
+            recorder.push_frame(timestamp_ms, format!("<{timestamp_ms}>").as_bytes())?;
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn test_segments() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        let config = config(dir.path());
+        record(&config)?;
+
+        let stream_dir = dir.path().join("hallway");
+        assert_eq!(list_segments(&stream_dir)?, vec![0, 1000, 2000]);
+        assert_eq!(
+            std::fs::read_to_string(stream_dir.join("1000.mjpeg"))?,
+            "<1000><1250><1500><1750>"
+        );
+        assert_eq!(
+            std::fs::read_to_string(stream_dir.join("1000.idx"))?,
+            "1000 0 6\n1250 6 6\n1500 12 6\n1750 18 6\n"
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_find_frames() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        let config = config(dir.path());
+        record(&config)?;
+
+        // Ranges spanning segments, within a segment and without frames
+        let timestamps = |from_ms, to_ms| -> Result<Vec<u64>> {
+            Ok(find_frames(&config, "hallway", from_ms, to_ms)?
+                .into_iter()
+                .map(|frame| frame.timestamp_ms)
+                .collect())
+        };
+        assert_eq!(timestamps(700, 1300)?, vec![750, 1000, 1250]);
+        assert_eq!(timestamps(2100, 2600)?, vec![2250, 2500]);
+        assert!(timestamps(5000, 6000)?.is_empty());
+        assert!(find_frames(&config, "lobby", 0, 3000)?.is_empty());
+
+        // Incomplete index lines are skipped
+        let path = Arc::new(PathBuf::from("0.mjpeg"));
+        assert!(parse_index_line("1000 0", &path).is_none());
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_play_frames_accelerated() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        let config = config(dir.path());
+        record(&config)?;
+
+        let frames = find_frames(&config, "hallway", 1000, 2000)?;
+        let start = Instant::now();
+        let played: Vec<_> = play_frames(frames, 10.0)
+            .map(|data| data.unwrap())
+            .collect()
+            .await;
+
+        assert_eq!(played.first().unwrap(), "<1000>");
+        assert_eq!(played.last().unwrap(), "<2000>");
+        assert_eq!(played.len(), 5);
+        // One second of footage at tenfold speed
+        let elapsed = start.elapsed();
+        assert!(
+            elapsed >= Duration::from_millis(100) && elapsed < Duration::from_millis(500),
+            "{elapsed:?}"
+        );
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_play_frames_tiny_speed() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        let config = config(dir.path());
+        record(&config)?;
+
+        // The second frame would be due after the end of time, which fails instead of panicking
+        let frames = find_frames(&config, "hallway", 1000, 1250)?;
+        let played: Vec<_> = play_frames(frames, 1e-30).collect().await;
+        assert_eq!(played.len(), 2);
+        assert!(played[0].is_ok());
+        assert!(played[1].is_err());
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_validate_range() {
+        assert!(validate_range(1000, 2000).is_ok());
+        assert!(validate_range(2000, 1000).is_err());
+        assert!(validate_range(0, u64::MAX).is_err());
+    }
+
+    #[test]
+    fn test_retention() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        let config = config(dir.path());
+        record(&config)?;
+
+        // Segments of 4 frames of 6 bytes with 4 index lines of about 10 bytes
+        let mut recorder = SegmentRecorder::new("lobby", config.clone());
+        recorder.push_frame(2500, b"<2500>")?;
+
+        // The segment of the hallway at 0 is too old, the latest segments are always kept
+        assert_eq!(enforce_retention(&config, 11_500)?, 1);
+        assert_eq!(
+            list_segments(&dir.path().join("hallway"))?,
+            vec![1000, 2000]
+        );
+        assert!(!dir.path().join("hallway").join("0.mjpeg").exists());
+
+        // The oldest segment is deleted once the size is exceeded
+        let config = RecordingConfig {
+            max_bytes: 100,
+            ..config
+        };
+        assert_eq!(enforce_retention(&config, 11_500)?, 1);
+        assert_eq!(list_segments(&dir.path().join("hallway"))?, vec![2000]);
+        assert_eq!(list_segments(&dir.path().join("lobby"))?, vec![2500]);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_validate_speed() {
+        assert!(validate_speed(1.0).is_ok());
+        assert!(validate_speed(MAX_SPEED).is_ok());
+        assert!(validate_speed(0.0).is_err());
+        assert!(validate_speed(1e-30).is_err());
+        assert!(validate_speed(f32::INFINITY).is_err());
+        assert!(validate_speed(f32::NAN).is_err());
+    }
+}
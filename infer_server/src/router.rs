@@ -1,53 +1,111 @@
-use std::{collections::HashMap, sync::Mutex};
+use std::{
+    collections::HashMap,
+    sync::{Arc, Mutex, Weak},
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
+};
 
-use anyhow::{bail, Result};
+use anyhow::{anyhow, bail, Result};
+use bytes::Bytes;
 use common::protocol::ProtoMsg;
+use tokio::sync::{broadcast, watch};
 
 use crate::{
-    broadcast_channel, hashed, BroadcastReceiver, BroadcastSender, StaticFrameReceiver,
-    StaticImageSender,
+    anonymize::Anonymization,
+    broadcast_channel,
+    counting::{SharedCounter, StreamCounter, StreamCounts},
+    detections::FrameDetections,
+    hashed,
+    inferer::annotate_jpeg,
+    scheduler::InferScheduler,
+    smoothing::Smoothing,
+    tracking::{SharedTracker, Tracker, TrackerConfig},
+    zones::StreamZones,
+    BroadcastReceiver, BroadcastSender, DetectionsReceiver, DetectionsSender, LatestDetections,
+    StaticFrameReceiver, StaticImage,
 };
 
-use super::as_jpeg_stream_item;
+use super::{as_infered_stream_item, as_jpeg_stream_item};
 
 pub struct FrameRouter {
     frames_broadcast_map: Mutex<HashMap<u64, BroadcastSender>>,
     infered_broadcast_map: Mutex<HashMap<u64, BroadcastSender>>,
-    infer_tx: StaticImageSender,
+    detections_broadcast_map: Mutex<HashMap<u64, DetectionsSender>>,
+    anonymized_broadcast_map: Mutex<HashMap<u64, BroadcastSender>>,
+    infer_options_map: Mutex<HashMap<u64, Vec<ViewerOptions>>>,
+    anonymization_map: Mutex<HashMap<u64, Anonymization>>,
+    zones_map: Mutex<HashMap<u64, Arc<StreamZones>>>,
+    counters_map: Mutex<HashMap<u64, SharedCounter>>,
+    scheduler: Arc<InferScheduler>,
+    tracker_config: TrackerConfig,
+    raw_streams: bool,
 }
 
 impl FrameRouter {
-    pub fn new(infer_tx: StaticImageSender) -> Self {
+    pub fn new(scheduler: Arc<InferScheduler>) -> Self {
         Self {
             frames_broadcast_map: Mutex::new(HashMap::new()),
             infered_broadcast_map: Mutex::new(HashMap::new()),
-            infer_tx,
+            detections_broadcast_map: Mutex::new(HashMap::new()),
+            anonymized_broadcast_map: Mutex::new(HashMap::new()),
+            infer_options_map: Mutex::new(HashMap::new()),
+            anonymization_map: Mutex::new(HashMap::new()),
+            zones_map: Mutex::new(HashMap::new()),
+            counters_map: Mutex::new(HashMap::new()),
+            scheduler,
+            tracker_config: TrackerConfig::default(),
+            raw_streams: true,
         }
     }
 
+    /// Allow or forbid access to streams showing unanonymized frames.
+    pub fn with_raw_streams(mut self, raw_streams: bool) -> Self {
+        self.raw_streams = raw_streams;
+        self
+    }
+
+    /// Whether streams showing unanonymized frames may be served.
+    pub fn raw_streams_enabled(&self) -> bool {
+        self.raw_streams
+    }
+
+    /// Track the objects of every stream with the given birth and death rules.
+    pub fn with_tracker_config(mut self, tracker_config: TrackerConfig) -> Self {
+        self.tracker_config = tracker_config;
+        self
+    }
+
     pub async fn run(&self, rx: StaticFrameReceiver) -> Result<()> {
         let mut frames_sender_map = HashMap::new();
         let mut infered_sender_map = HashMap::new();
+        let mut detections_sender_map = HashMap::new();
+        let mut anonymized_sender_map = HashMap::new();
+        let mut infer_options_map = HashMap::new();
+        let mut anonymization_map = HashMap::new();
+        let mut zones_map = HashMap::new();
+        let mut counters_map = HashMap::new();
+        let mut seq_map: HashMap<u64, u64> = HashMap::new();
+        let mut last_infered_map: HashMap<u64, Instant> = HashMap::new();
+        let mut latest_detections_map: HashMap<u64, LatestDetections> = HashMap::new();
+        let mut reuse_map: HashMap<u64, ReuseSender> = HashMap::new();
+        let mut tracker_map: HashMap<u64, SharedTracker> = HashMap::new();
 
         loop {
-            {
-                let mut frames_broadcast_map = self.frames_broadcast_map.lock().unwrap();
-                frames_broadcast_map.retain(|_id, sender| sender.receiver_count() > 0);
-
-                for (id, sender) in frames_broadcast_map.iter() {
-                    frames_sender_map.insert(*id, sender.clone());
-                }
-                frames_sender_map.retain(|id, _sender| frames_broadcast_map.contains_key(id))
-            }
-            {
-                let mut infered_broadcast_map = self.infered_broadcast_map.lock().unwrap();
-                infered_broadcast_map.retain(|_id, sender| sender.receiver_count() > 0);
-
-                for (id, sender) in infered_broadcast_map.iter() {
-                    infered_sender_map.insert(*id, sender.clone());
-                }
-                infered_sender_map.retain(|id, _sender| infered_broadcast_map.contains_key(id))
-            }
+            refresh_sender_map(&self.frames_broadcast_map, &mut frames_sender_map);
+            refresh_sender_map(&self.infered_broadcast_map, &mut infered_sender_map);
+            refresh_sender_map(&self.detections_broadcast_map, &mut detections_sender_map);
+            refresh_sender_map(&self.anonymized_broadcast_map, &mut anonymized_sender_map);
+            self.refresh_infer_options_map(&mut infer_options_map);
+            anonymization_map.clone_from(&self.anonymization_map.lock().unwrap());
+            zones_map.clone_from(&self.zones_map.lock().unwrap());
+            counters_map.clone_from(&self.counters_map.lock().unwrap());
+            latest_detections_map.retain(|id, _latest| infer_options_map.contains_key(id));
+            reuse_map.retain(|id, _reuse| infer_options_map.contains_key(id));
+            tracker_map.retain(|id, _tracker| {
+                infered_sender_map.contains_key(id)
+                    || detections_sender_map.contains_key(id)
+                    || anonymized_sender_map.contains_key(id)
+                    || zones_map.contains_key(id)
+            });
 
             for _ in 0..4 {
                 match rx.recv_ref().await {
@@ -57,18 +115,94 @@ impl FrameRouter {
                         {
                             let id = hashed(&proto_msg.id);
 
+                            let seq = seq_map.entry(id).or_default();
+                            *seq += 1;
+
                             if let Some(sender) = frames_sender_map.get(&id) {
                                 sender.send(as_jpeg_stream_item(&proto_msg.data)).ok();
                             }
 
-                            if let Some(sender) = infered_sender_map.get(&id) {
-                                if let Ok(mut frame) = self.infer_tx.try_send_ref() {
-                                    frame.0 = 1280;
-                                    frame.1 = 720;
-                                    frame.2.clear();
-                                    frame.2.extend_from_slice(&proto_msg.data);
-                                    frame.3 = Some(sender.clone());
+                            // Only infer frames of streams which someone is listening to or which
+                            // are counted
+                            let infered_sender = infered_sender_map.get(&id);
+                            let detections_sender = detections_sender_map.get(&id);
+                            let anonymized_sender = anonymized_sender_map.get(&id);
+                            let counter = zones_map
+                                .contains_key(&id)
+                                .then(|| counters_map.get(&id))
+                                .flatten();
+                            if infered_sender.is_some()
+                                || detections_sender.is_some()
+                                || anonymized_sender.is_some()
+                                || counter.is_some()
+                            {
+                                let options: InferOptions =
+                                    infer_options_map.get(&id).copied().unwrap_or_default();
+
+                                // Take the frame size from the JPEG header so that streams with
+                                // different resolutions can be infered side by side
+                                let header = match turbojpeg::read_header(&proto_msg.data) {
+                                    Ok(header) => header,
+                                    Err(err) => {
+                                        log::warn!("Skipping frame with invalid JPEG: {err}");
+                                        continue;
+                                    }
+                                };
+
+                                let frame = StaticImage {
+                                    stream_id: id,
+                                    width: header.width as u32,
+                                    height: header.height as u32,
+                                    data: Bytes::from(proto_msg.data),
+                                    seq: *seq,
+                                    timestamp_ms: timestamp_ms(),
+                                    infered_tx: infered_sender.cloned(),
+                                    detections_tx: detections_sender.cloned(),
+                                    latest_detections: options
+                                        .reuse_detections
+                                        .then(|| latest_detections_map.entry(id).or_default())
+                                        .cloned(),
+                                    tracker: Some(
+                                        tracker_map
+                                            .entry(id)
+                                            .or_insert_with(|| {
+                                                // Ids of the new tracker start over
+                                                if let Some(counter) = counters_map.get(&id) {
+                                                    counter.lock().unwrap().reset_tracks();
+                                                }
+                                                Arc::new(Mutex::new(Tracker::new(
+                                                    self.tracker_config,
+                                                )))
+                                            })
+                                            .clone(),
+                                    ),
+                                    smoothing: options.smoothing,
+                                    anonymized_tx: anonymized_sender.cloned(),
+                                    anonymization: anonymization_map
+                                        .get(&id)
+                                        .copied()
+                                        .unwrap_or_default(),
+                                    zones: zones_map.get(&id).cloned(),
+                                    counter: counter.cloned(),
+                                };
+
+                                // Skip frames to keep the inference rate of the stream below its cap
+                                let now = Instant::now();
+                                if let (Some(interval), Some(last_infered)) =
+                                    (options.min_interval, last_infered_map.get(&id))
+                                {
+                                    if now.duration_since(*last_infered) < interval {
+                                        reuse_latest_detections(&mut reuse_map, id, frame);
+                                        continue;
+                                    }
+                                }
+
+                                if let Some(replaced) =
+                                    self.scheduler.push(id, &proto_msg.id, frame)
+                                {
+                                    reuse_latest_detections(&mut reuse_map, id, replaced);
                                 }
+                                last_infered_map.insert(id, now);
                             }
                         }
                     }
@@ -138,4 +272,542 @@ impl FrameRouter {
             })
             .clone()
     }
+
+    /// Add the inference options of a viewer of a stream.
+    ///
+    /// The options apply as long as the returned guard is alive. Since all viewers share the
+    /// infered stream, the options of all of them are combined with [`InferOptions::combine`].
+    #[must_use]
+    pub fn set_infer_options(&self, name: &str, options: InferOptions) -> InferOptionsGuard {
+        let id = hashed(name);
+        let viewer = Arc::new(());
+        let mut infer_options_map = self.infer_options_map.lock().unwrap();
+
+        infer_options_map
+            .entry(id)
+            .or_default()
+            .push((Arc::downgrade(&viewer), options));
+
+        InferOptionsGuard { _viewer: viewer }
+    }
+
+    /// Drop options of viewers which are gone and mirror the combined options of every stream.
+    fn refresh_infer_options_map(&self, options_map: &mut HashMap<u64, InferOptions>) {
+        let mut infer_options_map = self.infer_options_map.lock().unwrap();
+        infer_options_map.retain(|_id, viewers| {
+            viewers.retain(|(viewer, _options)| viewer.strong_count() > 0);
+            !viewers.is_empty()
+        });
+
+        options_map.clear();
+        options_map.extend(infer_options_map.iter().filter_map(|(id, viewers)| {
+            let options = InferOptions::combine(viewers.iter().map(|(_viewer, options)| options));
+            (options != InferOptions::default()).then_some((*id, options))
+        }));
+    }
+
+    /// Subscribe to the anonymized frames of a stream.
+    ///
+    /// The frames are anonymized as configured for the stream with [`Self::set_anonymization`],
+    /// viewers cannot weaken it.
+    pub fn get_anonymized_receiver(&self, name: &str) -> BroadcastReceiver {
+        let id = hashed(name);
+        let mut anonymized_broadcast_map = self.anonymized_broadcast_map.lock().unwrap();
+
+        if let Some(tx) = anonymized_broadcast_map.get(&id) {
+            tx.subscribe()
+        } else {
+            let (tx, rx) = broadcast_channel();
+            anonymized_broadcast_map.insert(id, tx);
+
+            rx
+        }
+    }
+
+    /// Set the anonymization of a stream, streams without one use the default anonymization.
+    pub fn set_anonymization(&self, name: &str, anonymization: Anonymization) {
+        self.anonymization_map
+            .lock()
+            .unwrap()
+            .insert(hashed(name), anonymization);
+    }
+
+    /// Set the zones of a stream, applying from the next frame on.
+    ///
+    /// Tracked objects are forgotten by the counter of the stream, so that they are counted
+    /// against the new zones only.
+    pub fn set_zones(&self, name: &str, zones: StreamZones) {
+        let id = hashed(name);
+        let mut zones_map = self.zones_map.lock().unwrap();
+        let mut counters_map = self.counters_map.lock().unwrap();
+
+        if zones.is_empty() {
+            zones_map.remove(&id);
+        } else {
+            zones_map.insert(id, Arc::new(zones));
+            counters_map
+                .entry(id)
+                .or_insert_with(|| Arc::new(Mutex::new(StreamCounter::new(name))));
+        }
+
+        if let Some(counter) = counters_map.get(&id) {
+            counter.lock().unwrap().reset_tracks();
+        }
+    }
+
+    /// Get the counts of all streams which ever had zones or lines, sorted by name.
+    pub fn counts(&self) -> Vec<StreamCounts> {
+        let counters_map = self.counters_map.lock().unwrap();
+        let mut counts: Vec<_> = counters_map
+            .values()
+            .map(|counter| counter.lock().unwrap().counts())
+            .collect();
+        counts.sort_by(|a, b| a.name.cmp(&b.name));
+
+        counts
+    }
+
+    pub fn get_zones(&self, name: &str) -> StreamZones {
+        let id = hashed(name);
+        let zones_map = self.zones_map.lock().unwrap();
+
+        zones_map
+            .get(&id)
+            .map(|zones| zones.as_ref().clone())
+            .unwrap_or_default()
+    }
+
+    pub fn get_detections_receiver(&self, name: &str) -> DetectionsReceiver {
+        let id = hashed(name);
+        let mut detections_broadcast_map = self.detections_broadcast_map.lock().unwrap();
+
+        if let Some(tx) = detections_broadcast_map.get(&id) {
+            tx.subscribe()
+        } else {
+            let (tx, rx) = broadcast_channel();
+            detections_broadcast_map.insert(id, tx);
+
+            rx
+        }
+    }
+}
+
+/// Options of the inference of a stream.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct InferOptions {
+    /// Minimum interval between two infered frames, `None` infers every frame.
+    pub min_interval: Option<Duration>,
+    /// Send frames which are not infered to the infered stream with the latest detections.
+    pub reuse_detections: bool,
+    /// Smooth the bounding boxes of tracked objects over time.
+    pub smoothing: Option<Smoothing>,
+}
+
+impl InferOptions {
+    /// Combine the options of all viewers of a stream into the most permissive ones.
+    ///
+    /// The inference rate is only capped if every viewer asked for a cap, and then by the highest
+    /// requested rate. Detections are reused if any viewer asked for it. The smoothing of the
+    /// viewer which joined last applies.
+    pub fn combine<'a>(options: impl IntoIterator<Item = &'a InferOptions>) -> Self {
+        let mut options = options.into_iter();
+        let Some(first) = options.next().copied() else {
+            return Self::default();
+        };
+
+        options.fold(first, |combined, options| Self {
+            min_interval: combined
+                .min_interval
+                .zip(options.min_interval)
+                .map(|(a, b)| a.min(b)),
+            reuse_detections: combined.reuse_detections || options.reuse_detections,
+            smoothing: options.smoothing.or(combined.smoothing),
+        })
+    }
+
+    /// Options capping the inference rate at `max_fps` frames per second.
+    pub fn with_max_fps(mut self, max_fps: Option<f32>) -> Result<Self> {
+        self.min_interval = max_fps
+            .map(|max_fps| {
+                if !(max_fps.is_finite() && max_fps > 0.0) {
+                    bail!("max_fps has to be a positive number");
+                }
+                Duration::try_from_secs_f32(1.0 / max_fps)
+                    .map_err(|_err| anyhow!("max_fps {max_fps} is too small"))
+            })
+            .transpose()?;
+        Ok(self)
+    }
+
+    pub fn with_reuse_detections(mut self, reuse_detections: bool) -> Self {
+        self.reuse_detections = reuse_detections;
+        self
+    }
+
+    pub fn with_smoothing(mut self, smoothing: Option<Smoothing>) -> Self {
+        self.smoothing = smoothing;
+        self
+    }
+}
+
+/// Inference options of a viewer which apply while the viewer is alive.
+type ViewerOptions = (Weak<()>, InferOptions);
+
+/// Keeps the inference options of a viewer applied until it is dropped.
+pub struct InferOptionsGuard {
+    _viewer: Arc<()>,
+}
+
+/// Latest-wins slot of a stream for frames to send with its latest detections.
+type ReuseSender = watch::Sender<Option<StaticImage>>;
+
+/// Hand a frame which is not infered to the reuse task of its stream.
+///
+/// Only frames of streams in the reuse mode are sent, all others are dropped.
+fn reuse_latest_detections(reuse_map: &mut HashMap<u64, ReuseSender>, id: u64, frame: StaticImage) {
+    if frame.latest_detections.is_some() {
+        reuse_map
+            .entry(id)
+            .or_insert_with(spawn_reuse_task)
+            .send_replace(Some(frame));
+    }
+}
+
+/// Spawn a task sending frames with the latest detections of a stream one by one and in order.
+///
+/// A frame waiting while the previous one is annotated is replaced by newer frames, so that a
+/// slow annotation drops frames instead of piling them up. The task ends with its sender.
+fn spawn_reuse_task() -> ReuseSender {
+    let (tx, mut rx) = watch::channel(None);
+
+    tokio::spawn(async move {
+        while rx.changed().await.is_ok() {
+            let Some(frame) = rx.borrow_and_update().clone() else {
+                continue;
+            };
+
+            // Decoding and encoding is too expensive to run on the router task
+            if let Err(err) =
+                tokio::task::spawn_blocking(move || send_with_latest_detections(frame)).await
+            {
+                log::warn!("Failed to send latest detections: {err}");
+            }
+        }
+    });
+
+    tx
+}
+
+/// Draw the latest detections of a stream on a frame which is not infered and send it.
+fn send_with_latest_detections(frame: StaticImage) {
+    let Some(latest_detections) = frame.latest_detections.as_ref() else {
+        return;
+    };
+    let detections = latest_detections.lock().unwrap().clone();
+
+    if let Some(detections_tx) = frame.detections_tx.as_ref() {
+        detections_tx
+            .send(
+                FrameDetections::new(
+                    frame.seq,
+                    frame.timestamp_ms,
+                    frame.width,
+                    frame.height,
+                    &detections,
+                    frame.data.clone(),
+                )
+                .reused(),
+            )
+            .ok();
+    }
+
+    if let Some(infered_tx) = frame.infered_tx.as_ref() {
+        match annotate_jpeg(
+            &frame.data,
+            &detections,
+            frame.zones.as_deref(),
+            frame.width,
+            frame.height,
+        ) {
+            Ok(buf) => {
+                infered_tx.send(as_infered_stream_item(&buf, false)).ok();
+            }
+            Err(err) => log::warn!("Failed to draw latest detections: {err}"),
+        }
+    }
+}
+
+/// Drop broadcast senders without receivers and mirror the remaining ones in `sender_map`.
+///
+/// Keeping a local copy of the senders avoids locking the shared map for every frame.
+fn refresh_sender_map<T>(
+    broadcast_map: &Mutex<HashMap<u64, broadcast::Sender<T>>>,
+    sender_map: &mut HashMap<u64, broadcast::Sender<T>>,
+) {
+    let mut broadcast_map = broadcast_map.lock().unwrap();
+    broadcast_map.retain(|_id, sender| sender.receiver_count() > 0);
+
+    for (id, sender) in broadcast_map.iter() {
+        sender_map.insert(*id, sender.clone());
+    }
+    sender_map.retain(|id, _sender| broadcast_map.contains_key(id))
+}
+
+/// Get the current time in milliseconds since the UNIX epoch.
+pub(crate) fn timestamp_ms() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|duration| duration.as_millis() as u64)
+        .unwrap_or_default()
+}
+
+#[cfg(test)]
+mod test {
+
+    use std::sync::Arc;
+
+    use bytes::BytesMut;
+    use common::protocol::FrameMsg;
+    use image::RgbImage;
+    use thingbuf::mpsc::StaticChannel;
+
+    use super::*;
+    use crate::anonymize::AnonymizeMode;
+
+    static TEST_INCOMING_CHANNEL: StaticChannel<BytesMut, 200> = StaticChannel::new();
+
+    /// Wait on a blocking thread for the next frame to infer and mark it as done right away.
+    async fn next_frame(scheduler: &Arc<InferScheduler>) -> StaticImage {
+        let scheduler = scheduler.clone();
+        tokio::task::spawn_blocking(move || {
+            let frame = scheduler.next().unwrap();
+            scheduler.done(frame.stream_id);
+            frame
+        })
+        .await
+        .unwrap()
+    }
+
+    fn jpeg_frame_msg(name: &str, width: u32, height: u32) -> BytesMut {
+        let image = RgbImage::new(width, height);
+        let jpeg = turbojpeg::compress_image(&image, 95, turbojpeg::Subsamp::Sub2x2).unwrap();
+        let msg = ProtoMsg::FrameMsg(FrameMsg::new(name.into(), jpeg.to_vec()));
+
+        BytesMut::from(&bincode::serialize(&msg).unwrap()[..])
+    }
+
+    #[tokio::test]
+    async fn test_frame_sizes_from_jpeg_header() {
+        let (incoming_tx, incoming_rx) = TEST_INCOMING_CHANNEL.split();
+        let scheduler = Arc::new(InferScheduler::new());
+        let frame_router = Arc::new(FrameRouter::new(scheduler.clone()));
+
+        let sizes = [
+            ("vga", 640, 480),
+            ("hd", 1280, 720),
+            ("full_hd", 1920, 1080),
+        ];
+
+        // Subscribe to all infered streams before the router picks up the senders
+        let _receivers: Vec<_> = sizes
+            .iter()
+            .map(|(name, _, _)| frame_router.get_infered_receiver(name))
+            .collect();
+
+        {
+            let frame_router = frame_router.clone();
+            tokio::spawn(async move { frame_router.run(incoming_rx).await });
+        }
+
+        for (name, width, height) in sizes {
+            incoming_tx
+                .send(jpeg_frame_msg(name, width, height))
+                .await
+                .unwrap();
+
+            let frame = next_frame(&scheduler).await;
+            assert_eq!((frame.width, frame.height), (width, height));
+        }
+    }
+
+    static TEST_DETECTIONS_INCOMING_CHANNEL: StaticChannel<BytesMut, 200> = StaticChannel::new();
+
+    #[tokio::test]
+    async fn test_detections_subscription() {
+        let (incoming_tx, incoming_rx) = TEST_DETECTIONS_INCOMING_CHANNEL.split();
+        let scheduler = Arc::new(InferScheduler::new());
+        let frame_router = Arc::new(FrameRouter::new(scheduler.clone()));
+
+        // Frames are only infered once someone listens to the detections
+        let _rx = frame_router.get_detections_receiver("cam");
+
+        {
+            let frame_router = frame_router.clone();
+            tokio::spawn(async move { frame_router.run(incoming_rx).await });
+        }
+
+        for expected_seq in 1..=3 {
+            incoming_tx
+                .send(jpeg_frame_msg("cam", 320, 240))
+                .await
+                .unwrap();
+
+            let frame = next_frame(&scheduler).await;
+            assert_eq!(frame.seq, expected_seq);
+            assert!(frame.detections_tx.is_some());
+            assert!(frame.infered_tx.is_none());
+        }
+    }
+
+    static TEST_ANONYMIZED_INCOMING_CHANNEL: StaticChannel<BytesMut, 200> = StaticChannel::new();
+
+    #[tokio::test]
+    async fn test_anonymized_subscription() {
+        let (incoming_tx, incoming_rx) = TEST_ANONYMIZED_INCOMING_CHANNEL.split();
+        let scheduler = Arc::new(InferScheduler::new());
+        let frame_router = Arc::new(FrameRouter::new(scheduler.clone()).with_raw_streams(false));
+        assert!(!frame_router.raw_streams_enabled());
+
+        // Frames are infered for the anonymized stream only, with its anonymization
+        let anonymization = Anonymization::new(AnonymizeMode::Pixelate, 0.25).unwrap();
+        frame_router.set_anonymization("lobby", anonymization);
+        let _rx = frame_router.get_anonymized_receiver("lobby");
+
+        {
+            let frame_router = frame_router.clone();
+            tokio::spawn(async move { frame_router.run(incoming_rx).await });
+        }
+
+        incoming_tx
+            .send(jpeg_frame_msg("lobby", 320, 240))
+            .await
+            .unwrap();
+
+        let frame = next_frame(&scheduler).await;
+        assert!(frame.anonymized_tx.is_some());
+        assert!(frame.infered_tx.is_none());
+        assert_eq!(frame.anonymization, anonymization);
+    }
+
+    static TEST_MAX_FPS_INCOMING_CHANNEL: StaticChannel<BytesMut, 200> = StaticChannel::new();
+
+    #[test]
+    fn test_max_fps_validation() {
+        let options = InferOptions::default().with_max_fps(Some(4.0)).unwrap();
+        assert_eq!(options.min_interval, Some(Duration::from_millis(250)));
+
+        // Tiny rates overflow the interval instead of panicking
+        for max_fps in [0.0, -1.0, f32::NAN, f32::INFINITY, 1e-20, 1e-39] {
+            assert!(InferOptions::default().with_max_fps(Some(max_fps)).is_err());
+        }
+    }
+
+    #[tokio::test]
+    async fn test_max_infer_fps() -> Result<()> {
+        let (incoming_tx, incoming_rx) = TEST_MAX_FPS_INCOMING_CHANNEL.split();
+        let scheduler = Arc::new(InferScheduler::new());
+        let frame_router = Arc::new(FrameRouter::new(scheduler.clone()));
+
+        let mut raw_rx = frame_router.get_broadcast_receiver("hallway");
+        let _infered_rx = frame_router.get_infered_receiver("hallway");
+        let _viewer = frame_router
+            .set_infer_options("hallway", InferOptions::default().with_max_fps(Some(1.0))?);
+
+        {
+            let frame_router = frame_router.clone();
+            tokio::spawn(async move { frame_router.run(incoming_rx).await });
+        }
+
+        // The raw stream keeps the full rate while only the first frame is infered
+        for _ in 0..3 {
+            incoming_tx
+                .send(jpeg_frame_msg("hallway", 320, 240))
+                .await
+                .unwrap();
+            raw_rx.recv().await.unwrap();
+        }
+
+        assert_eq!(next_frame(&scheduler).await.seq, 1);
+        assert_eq!(scheduler.stats()[0].dropped, 0);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_combined_infer_options() -> Result<()> {
+        let frame_router = FrameRouter::new(Arc::new(InferScheduler::new()));
+        let mut options_map = HashMap::new();
+        let id = hashed("hallway");
+
+        let capped = frame_router
+            .set_infer_options("hallway", InferOptions::default().with_max_fps(Some(1.0))?);
+        let faster = frame_router
+            .set_infer_options("hallway", InferOptions::default().with_max_fps(Some(4.0))?);
+        frame_router.refresh_infer_options_map(&mut options_map);
+        assert_eq!(
+            options_map[&id].min_interval,
+            Some(Duration::from_millis(250))
+        );
+
+        // A viewer without a cap lifts it until it leaves
+        let uncapped = frame_router.set_infer_options("hallway", InferOptions::default());
+        frame_router.refresh_infer_options_map(&mut options_map);
+        assert!(!options_map.contains_key(&id));
+
+        drop(uncapped);
+        drop(faster);
+        frame_router.refresh_infer_options_map(&mut options_map);
+        assert_eq!(options_map[&id].min_interval, Some(Duration::from_secs(1)));
+
+        drop(capped);
+        frame_router.refresh_infer_options_map(&mut options_map);
+        assert!(options_map.is_empty());
+
+        Ok(())
+    }
+
+    static TEST_REUSE_INCOMING_CHANNEL: StaticChannel<BytesMut, 200> = StaticChannel::new();
+
+    #[tokio::test]
+    async fn test_reuse_detections() -> Result<()> {
+        let (incoming_tx, incoming_rx) = TEST_REUSE_INCOMING_CHANNEL.split();
+        let scheduler = Arc::new(InferScheduler::new());
+        let frame_router = Arc::new(FrameRouter::new(scheduler.clone()));
+
+        let mut infered_rx = frame_router.get_infered_receiver("door");
+        let mut detections_rx = frame_router.get_detections_receiver("door");
+        let _viewer = frame_router.set_infer_options(
+            "door",
+            InferOptions::default()
+                .with_max_fps(Some(1.0))?
+                .with_reuse_detections(true),
+        );
+
+        {
+            let frame_router = frame_router.clone();
+            tokio::spawn(async move { frame_router.run(incoming_rx).await });
+        }
+
+        // The first frame is waiting for inference, the others are sent with reused detections
+        // in order
+        for seq in 1..=3 {
+            incoming_tx
+                .send(jpeg_frame_msg("door", 320, 240))
+                .await
+                .unwrap();
+            if seq == 1 {
+                continue;
+            }
+
+            let item = infered_rx.recv().await.unwrap();
+            let item = String::from_utf8_lossy(&item);
+            assert!(item.contains("X-Detections: reused"));
+
+            let detections = detections_rx.recv().await.unwrap();
+            assert_eq!((detections.seq, detections.fresh), (seq, false));
+        }
+        assert_eq!(next_frame(&scheduler).await.seq, 1);
+
+        Ok(())
+    }
 }
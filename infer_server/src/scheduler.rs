@@ -0,0 +1,298 @@
+//! Fair scheduling of frames to infer across streams.
+//!
+use std::{
+    collections::{HashMap, HashSet, VecDeque},
+    sync::{Condvar, Mutex},
+    time::{Duration, Instant},
+};
+
+use serde::Serialize;
+
+use crate::StaticImage;
+
+/// Streams without a new frame for this long are forgotten along with their stats.
+const INACTIVE_TIMEOUT: Duration = Duration::from_secs(300);
+/// Interval between two checks for inactive streams.
+const PRUNE_INTERVAL: Duration = Duration::from_secs(10);
+
+/// Counts of scheduled and dropped frames of a stream.
+#[derive(Clone, Debug, Default, PartialEq, Serialize)]
+pub struct StreamStats {
+    pub name: String,
+    /// Frames handed to the inference workers.
+    pub scheduled: u64,
+    /// Frames replaced by a newer frame of the same stream before being infered.
+    pub dropped: u64,
+}
+
+#[derive(Default)]
+struct SchedulerState {
+    /// Latest frame of every stream waiting for inference.
+    pending: HashMap<u64, StaticImage>,
+    /// Streams with a pending frame in the order they are served.
+    queue: VecDeque<u64>,
+    /// Streams with a frame being infered, which are not served until it is done.
+    in_flight: HashSet<u64>,
+    stats: HashMap<u64, StreamStats>,
+    /// Time of the latest frame of every stream.
+    last_push: HashMap<u64, Instant>,
+    last_prune: Option<Instant>,
+    closed: bool,
+}
+
+/// Queue of frames to infer, serving streams round-robin with the latest frame of each.
+///
+/// Every stream has at most one pending frame. A new frame replaces the pending one of its
+/// stream ("latest wins"), so that no stream is infered on stale frames. A stream that got a frame
+/// infered queues up behind all other waiting streams, so that a fast camera cannot crowd out a
+/// slow one.
+///
+/// Only one frame of a stream is infered at a time, so that the results of a stream are published
+/// in order even with several workers. Workers report infered frames with [`Self::done`].
+#[derive(Default)]
+pub struct InferScheduler {
+    state: Mutex<SchedulerState>,
+    available: Condvar,
+}
+
+impl InferScheduler {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Schedule the latest frame of a stream, returning the pending frame it replaces.
+    pub fn push(&self, id: u64, name: &str, mut frame: StaticImage) -> Option<StaticImage> {
+        frame.stream_id = id;
+        let now = Instant::now();
+        let mut state = self.state.lock().unwrap();
+        state.last_push.insert(id, now);
+        if state
+            .last_prune
+            .is_none_or(|last_prune| now.duration_since(last_prune) >= PRUNE_INTERVAL)
+        {
+            state.prune(now);
+        }
+
+        let replaced = state.pending.insert(id, frame);
+
+        let stats = state.stats.entry(id).or_default();
+        if stats.name.is_empty() {
+            stats.name = name.to_owned();
+        }
+        if replaced.is_some() {
+            stats.dropped += 1;
+        } else if !state.in_flight.contains(&id) {
+            state.queue.push_back(id);
+            self.available.notify_one();
+        }
+
+        replaced
+    }
+
+    /// Mark the frame of a stream as infered and published, serving the stream again.
+    pub fn done(&self, id: u64) {
+        let mut state = self.state.lock().unwrap();
+        if state.in_flight.remove(&id) && state.pending.contains_key(&id) {
+            state.queue.push_back(id);
+            self.available.notify_one();
+        }
+    }
+
+    /// Wait for the next frame to infer, returns `None` once the scheduler is closed.
+    pub fn next(&self) -> Option<StaticImage> {
+        self.next_batch(1, Duration::ZERO)
+            .map(|mut batch| batch.remove(0))
+    }
+
+    /// Wait for the next batch of up to `batch_size` frames of different streams.
+    ///
+    /// After the first frame is available, it waits at most `max_wait` for the batch to fill up.
+    /// Returns `None` once the scheduler is closed and no frames are left.
+    pub fn next_batch(&self, batch_size: usize, max_wait: Duration) -> Option<Vec<StaticImage>> {
+        let mut state = self.state.lock().unwrap();
+        while state.queue.is_empty() {
+            if state.closed {
+                return None;
+            }
+            state = self.available.wait(state).unwrap();
+        }
+
+        let deadline = Instant::now() + max_wait;
+        let mut batch = Vec::with_capacity(batch_size);
+        loop {
+            while batch.len() < batch_size {
+                match state.pop() {
+                    Some(frame) => batch.push(frame),
+                    None => break,
+                }
+            }
+
+            let now = Instant::now();
+            if batch.len() >= batch_size || now >= deadline || state.closed {
+                break;
+            }
+            state = self
+                .available
+                .wait_timeout(state, deadline - now)
+                .unwrap()
+                .0;
+        }
+
+        Some(batch)
+    }
+
+    /// Stop scheduling, waking up all waiting workers.
+    pub fn close(&self) {
+        self.state.lock().unwrap().closed = true;
+        self.available.notify_all();
+    }
+
+    /// Get the frame counts of all active streams, sorted by name.
+    pub fn stats(&self) -> Vec<StreamStats> {
+        let mut state = self.state.lock().unwrap();
+        state.prune(Instant::now());
+
+        let mut stats: Vec<_> = state.stats.values().cloned().collect();
+        stats.sort_by(|a, b| a.name.cmp(&b.name));
+        stats
+    }
+}
+
+impl SchedulerState {
+    /// Take the pending frame of the next stream in line.
+    fn pop(&mut self) -> Option<StaticImage> {
+        let id = self.queue.pop_front()?;
+        let frame = self.pending.remove(&id)?;
+        self.in_flight.insert(id);
+        if let Some(stats) = self.stats.get_mut(&id) {
+            stats.scheduled += 1;
+        }
+
+        Some(frame)
+    }
+
+    /// Forget streams without a new frame within [`INACTIVE_TIMEOUT`], including pending frames.
+    fn prune(&mut self, now: Instant) {
+        self.last_push
+            .retain(|_id, last_push| now.saturating_duration_since(*last_push) < INACTIVE_TIMEOUT);
+
+        let last_push = &self.last_push;
+        self.stats.retain(|id, _stats| last_push.contains_key(id));
+        self.pending.retain(|id, _frame| last_push.contains_key(id));
+        let pending = &self.pending;
+        self.queue.retain(|id| pending.contains_key(id));
+        self.last_prune = Some(now);
+    }
+}
+
+#[cfg(test)]
+mod test {
+
+    use std::sync::Arc;
+
+    use super::*;
+
+    fn frame(seq: u64) -> StaticImage {
+        StaticImage {
+            seq,
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn test_latest_frame_wins() {
+        let scheduler = InferScheduler::new();
+        assert!(scheduler.push(1, "fast", frame(1)).is_none());
+        assert_eq!(scheduler.push(1, "fast", frame(2)).unwrap().seq, 1);
+        assert_eq!(scheduler.push(1, "fast", frame(3)).unwrap().seq, 2);
+
+        assert_eq!(scheduler.next().unwrap().seq, 3);
+        assert_eq!(
+            scheduler.stats(),
+            vec![StreamStats {
+                name: "fast".to_owned(),
+                scheduled: 1,
+                dropped: 2,
+            }]
+        );
+    }
+
+    #[test]
+    fn test_round_robin() {
+        let scheduler = InferScheduler::new();
+        scheduler.push(1, "fast", frame(1));
+        scheduler.push(2, "slow", frame(10));
+
+        // The fast stream is served first but has to queue up behind the slow one again
+        assert_eq!(scheduler.next().unwrap().seq, 1);
+        scheduler.push(1, "fast", frame(2));
+        scheduler.done(1);
+        assert_eq!(scheduler.next().unwrap().seq, 10);
+        assert_eq!(scheduler.next().unwrap().seq, 2);
+    }
+
+    #[test]
+    fn test_one_frame_in_flight_per_stream() {
+        let scheduler = InferScheduler::new();
+        scheduler.push(1, "fast", frame(1));
+        assert_eq!(scheduler.next().unwrap().seq, 1);
+
+        // The next frame of a stream waits until the previous one is done
+        scheduler.push(1, "fast", frame(2));
+        scheduler.push(2, "slow", frame(10));
+        let batch = scheduler.next_batch(4, Duration::from_millis(10)).unwrap();
+        let seqs: Vec<_> = batch.iter().map(|frame| frame.seq).collect();
+        assert_eq!(seqs, vec![10]);
+
+        scheduler.done(1);
+        let frame = scheduler.next().unwrap();
+        assert_eq!((frame.stream_id, frame.seq), (1, 2));
+    }
+
+    #[test]
+    fn test_next_batch() {
+        let scheduler = InferScheduler::new();
+        scheduler.push(1, "a", frame(1));
+        scheduler.push(1, "a", frame(2));
+        scheduler.push(2, "b", frame(3));
+
+        // A batch holds at most one frame per stream and does not wait for a full batch forever
+        let batch = scheduler.next_batch(4, Duration::from_millis(10)).unwrap();
+        let seqs: Vec<_> = batch.iter().map(|frame| frame.seq).collect();
+        assert_eq!(seqs, vec![2, 3]);
+    }
+
+    #[test]
+    fn test_prune_inactive_streams() {
+        let scheduler = InferScheduler::new();
+        scheduler.push(1, "gone", frame(1));
+        scheduler.push(2, "active", frame(2));
+        assert_eq!(scheduler.stats().len(), 2);
+
+        // Only the active stream pushed a frame recently
+        let later = Instant::now() + INACTIVE_TIMEOUT;
+        let mut state = scheduler.state.lock().unwrap();
+        state.last_push.insert(2, later);
+        state.prune(later);
+
+        assert!(!state.pending.contains_key(&1));
+        assert_eq!(state.queue, VecDeque::from([2]));
+        assert_eq!(state.stats.keys().collect::<Vec<_>>(), vec![&2]);
+        drop(state);
+
+        assert_eq!(scheduler.next().unwrap().seq, 2);
+    }
+
+    #[test]
+    fn test_close() {
+        let scheduler = Arc::new(InferScheduler::new());
+
+        let worker = {
+            let scheduler = scheduler.clone();
+            std::thread::spawn(move || scheduler.next().map(|frame| frame.seq))
+        };
+        scheduler.close();
+
+        assert_eq!(worker.join().unwrap(), None);
+    }
+}
@@ -0,0 +1,147 @@
+//! Temporal smoothing of the bounding boxes of tracked objects.
+//!
+use std::f32::consts::PI;
+
+use anyhow::{bail, Result};
+use serde::{Deserialize, Serialize};
+
+use crate::nn::Bbox;
+
+/// Speed coefficient of the one-euro filter in relative coordinates per second.
+///
+/// Fast moving boxes get a higher cutoff frequency and therefore less lag.
+const ONE_EURO_BETA: f32 = 10.0;
+/// Cutoff frequency in Hz of the speed estimate of the one-euro filter.
+const ONE_EURO_DERIVATIVE_CUTOFF: f32 = 1.0;
+/// Minimum time step between two frames, guarding against identical timestamps.
+const MIN_TIME_STEP_S: f32 = 1.0e-3;
+
+/// Filter used to smooth bounding boxes.
+#[derive(Clone, Copy, Debug, Deserialize, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum SmoothingMode {
+    /// Exponential moving average.
+    Ema,
+    /// One-euro filter, smoothing slow movements strongly and following fast ones closely.
+    OneEuro,
+}
+
+/// Smoothing of the bounding boxes of a stream.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Smoothing {
+    pub mode: SmoothingMode,
+    /// Strength between 0 (no smoothing) and 1 (boxes stand still), exclusive.
+    pub strength: f32,
+}
+
+impl Smoothing {
+    pub fn new(mode: SmoothingMode, strength: f32) -> Result<Self> {
+        if !(strength > 0.0 && strength < 1.0) {
+            bail!("smoothing strength has to be between 0 and 1, got {strength}");
+        }
+
+        Ok(Self { mode, strength })
+    }
+}
+
+/// Smoothing state of the bounding box of a single track.
+#[derive(Clone, Copy, Debug)]
+pub struct BoxSmoother {
+    bbox: Bbox,
+    /// Estimated speed of the box coordinates per second, used by the one-euro filter.
+    speed: [f32; 4],
+    timestamp_ms: u64,
+}
+
+impl BoxSmoother {
+    pub fn new(bbox: Bbox, timestamp_ms: u64) -> Self {
+        Self {
+            bbox,
+            speed: [0.0; 4],
+            timestamp_ms,
+        }
+    }
+
+    /// Smooth the bounding box measured at `timestamp_ms`.
+    pub fn smooth(&mut self, smoothing: &Smoothing, bbox: &Bbox, timestamp_ms: u64) -> Bbox {
+        let dt =
+            (timestamp_ms.saturating_sub(self.timestamp_ms) as f32 / 1000.0).max(MIN_TIME_STEP_S);
+        self.timestamp_ms = timestamp_ms;
+
+        for (i, value) in bbox.iter().enumerate() {
+            let alpha = match smoothing.mode {
+                SmoothingMode::Ema => 1.0 - smoothing.strength,
+                SmoothingMode::OneEuro => {
+                    let speed = (value - self.bbox[i]) / dt;
+                    let speed_alpha = cutoff_alpha(ONE_EURO_DERIVATIVE_CUTOFF, dt);
+                    self.speed[i] += speed_alpha * (speed - self.speed[i]);
+
+                    let min_cutoff = (1.0 - smoothing.strength) / smoothing.strength;
+                    cutoff_alpha(min_cutoff + ONE_EURO_BETA * self.speed[i].abs(), dt)
+                }
+            };
+            self.bbox[i] += alpha * (value - self.bbox[i]);
+        }
+
+        self.bbox
+    }
+}
+
+/// Smoothing factor of a low-pass filter with the cutoff frequency in Hz and time step in seconds.
+fn cutoff_alpha(cutoff: f32, dt: f32) -> f32 {
+    let tau = 1.0 / (2.0 * PI * cutoff);
+    1.0 / (1.0 + tau / dt)
+}
+
+#[cfg(test)]
+mod test {
+
+    use super::*;
+
+    #[test]
+    fn test_ema() {
+        let smoothing = Smoothing::new(SmoothingMode::Ema, 0.75).unwrap();
+        let mut smoother = BoxSmoother::new([0.0, 0.0, 0.5, 0.5], 0);
+
+        assert_eq!(
+            smoother.smooth(&smoothing, &[0.5, 0.0, 1.0, 0.5], 100),
+            [0.125, 0.0, 0.625, 0.5]
+        );
+        assert_eq!(
+            smoother.smooth(&smoothing, &[0.5, 0.0, 1.0, 0.5], 200),
+            [0.21875, 0.0, 0.71875, 0.5]
+        );
+    }
+
+    #[test]
+    fn test_one_euro_follows_fast_movements() {
+        let smoothing = Smoothing::new(SmoothingMode::OneEuro, 0.5).unwrap();
+        let bbox = [0.2, 0.2, 0.4, 0.4];
+
+        // Small jitter around a standing box is damped to less than half
+        let mut smoother = BoxSmoother::new(bbox, 0);
+        let jittered = smoother.smooth(&smoothing, &[0.21, 0.2, 0.41, 0.4], 100);
+        assert!(jittered[0] < 0.205, "{jittered:?}");
+
+        let mut smoother = BoxSmoother::new(bbox, 0);
+        let mut moved = bbox;
+        for step in 1..=5 {
+            let offset = 0.05 * step as f32;
+            moved = smoother.smooth(
+                &smoothing,
+                &[0.2 + offset, 0.2, 0.4 + offset, 0.4],
+                100 * step,
+            );
+        }
+        // A box moving fast is followed with a lag of less than half a step
+        assert!(0.45 - moved[0] < 0.025, "{moved:?}");
+    }
+
+    #[test]
+    fn test_smoothing_validation() {
+        assert!(Smoothing::new(SmoothingMode::Ema, 0.0).is_err());
+        assert!(Smoothing::new(SmoothingMode::OneEuro, 1.0).is_err());
+        assert!(Smoothing::new(SmoothingMode::OneEuro, f32::NAN).is_err());
+        assert!(Smoothing::new(SmoothingMode::Ema, 0.5).is_ok());
+    }
+}
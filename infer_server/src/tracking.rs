@@ -0,0 +1,412 @@
+//! Multi-object tracking of detections across the frames of a stream.
+//!
+//! Detections are associated with tracks by the IoU of their bounding boxes with the predicted
+//! bounding boxes of the tracks, similar to SORT. Every track predicts its bounding box with a
+//! constant-velocity Kalman filter on the box center and size.
+use std::{
+    collections::HashSet,
+    sync::{Arc, Mutex},
+};
+
+use anyhow::{bail, Result};
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    nn::{iou, validate_threshold, Bbox, Detection},
+    smoothing::{BoxSmoother, Smoothing},
+};
+
+/// Process noise of the position and velocity of the Kalman filters.
+const PROCESS_NOISE: [f32; 2] = [1.0e-5, 1.0e-5];
+/// Measurement noise of the Kalman filters in relative coordinates.
+const MEASUREMENT_NOISE: f32 = 1.0e-4;
+/// Initial variance of the velocity of new tracks.
+const INITIAL_VELOCITY_VARIANCE: f32 = 1.0e-2;
+
+/// Birth and death rules of tracks.
+#[derive(Clone, Copy, Debug, Deserialize, PartialEq, Serialize)]
+pub struct TrackerConfig {
+    /// Number of matched frames before a track is confirmed and gets an id in the outputs.
+    pub min_hits: u32,
+    /// Number of consecutive frames without a match after which a track is deleted.
+    pub max_misses: u32,
+    /// Minimum IoU of a detection with the predicted box of a track to be matched.
+    pub min_iou: f32,
+}
+
+impl Default for TrackerConfig {
+    fn default() -> Self {
+        Self {
+            min_hits: 3,
+            max_misses: 5,
+            min_iou: 0.3,
+        }
+    }
+}
+
+impl TrackerConfig {
+    pub fn new(min_hits: u32, max_misses: u32, min_iou: f32) -> Result<Self> {
+        if min_hits == 0 {
+            bail!("min_hits has to be at least 1");
+        }
+
+        Ok(Self {
+            min_hits,
+            max_misses,
+            min_iou: validate_threshold("min_iou", min_iou)?,
+        })
+    }
+}
+
+/// Tracker shared by the inference workers of a stream.
+pub type SharedTracker = Arc<Mutex<Tracker>>;
+
+/// Kalman filter of a position with constant velocity, measuring only the position.
+#[derive(Clone, Copy, Debug)]
+struct Kalman1d {
+    /// State `[position, velocity]`.
+    x: [f32; 2],
+    /// Covariance of the state.
+    p: [[f32; 2]; 2],
+}
+
+impl Kalman1d {
+    fn new(position: f32) -> Self {
+        Self {
+            x: [position, 0.0],
+            p: [[MEASUREMENT_NOISE, 0.0], [0.0, INITIAL_VELOCITY_VARIANCE]],
+        }
+    }
+
+    /// Predict the state one frame ahead.
+    fn predict(&mut self) {
+        let [[p00, p01], [p10, p11]] = self.p;
+        self.x = [self.x[0] + self.x[1], self.x[1]];
+        self.p = [
+            [p00 + p01 + p10 + p11 + PROCESS_NOISE[0], p01 + p11],
+            [p10 + p11, p11 + PROCESS_NOISE[1]],
+        ];
+    }
+
+    /// Correct the state with a measured position.
+    fn update(&mut self, position: f32) {
+        let [[p00, p01], [p10, p11]] = self.p;
+        let residual = position - self.x[0];
+        let gain = [
+            p00 / (p00 + MEASUREMENT_NOISE),
+            p10 / (p00 + MEASUREMENT_NOISE),
+        ];
+
+        self.x = [
+            self.x[0] + gain[0] * residual,
+            self.x[1] + gain[1] * residual,
+        ];
+        self.p = [
+            [(1.0 - gain[0]) * p00, (1.0 - gain[0]) * p01],
+            [p10 - gain[1] * p00, p11 - gain[1] * p01],
+        ];
+    }
+}
+
+/// Kalman filters of the center and size `[cx, cy, w, h]` of a bounding box.
+#[derive(Clone, Copy, Debug)]
+struct KalmanBox([Kalman1d; 4]);
+
+impl KalmanBox {
+    fn new(bbox: &Bbox) -> Self {
+        Self(center_size(bbox).map(Kalman1d::new))
+    }
+
+    fn predict(&mut self) -> Bbox {
+        self.0.iter_mut().for_each(Kalman1d::predict);
+        self.bbox()
+    }
+
+    fn update(&mut self, bbox: &Bbox) {
+        for (filter, value) in self.0.iter_mut().zip(center_size(bbox)) {
+            filter.update(value);
+        }
+    }
+
+    fn bbox(&self) -> Bbox {
+        let [cx, cy, w, h] = self.0.map(|filter| filter.x[0]);
+        [cx - w / 2.0, cy - h / 2.0, cx + w / 2.0, cy + h / 2.0]
+    }
+}
+
+/// Convert corner coordinates to center and size `[cx, cy, w, h]`.
+fn center_size(bbox: &Bbox) -> [f32; 4] {
+    [
+        (bbox[0] + bbox[2]) / 2.0,
+        (bbox[1] + bbox[3]) / 2.0,
+        bbox[2] - bbox[0],
+        bbox[3] - bbox[1],
+    ]
+}
+
+#[derive(Debug)]
+struct Track {
+    id: u64,
+    class_id: usize,
+    filter: KalmanBox,
+    /// Predicted bounding box in the current frame.
+    predicted: Bbox,
+    hits: u32,
+    misses: u32,
+    /// Smoothed bounding box of the track.
+    smoother: BoxSmoother,
+}
+
+/// Tracks of the objects in a single stream.
+#[derive(Debug, Default)]
+pub struct Tracker {
+    config: TrackerConfig,
+    tracks: Vec<Track>,
+    next_id: u64,
+    /// Sequence number of the last frame, older frames are not tracked.
+    last_seq: Option<u64>,
+}
+
+impl Tracker {
+    pub fn new(config: TrackerConfig) -> Self {
+        Self {
+            config,
+            ..Default::default()
+        }
+    }
+
+    /// Ids of all existing tracks, including the ones missed in the last frames.
+    pub fn track_ids(&self) -> HashSet<u64> {
+        self.tracks.iter().map(|track| track.id).collect()
+    }
+
+    /// Associate the detections of the frame `seq` with the tracks and set their track ids.
+    ///
+    /// Only detections of confirmed tracks get a track id. With `smoothing`, the bounding boxes of
+    /// matched detections are replaced by the smoothed boxes of their tracks.
+    ///
+    /// Returns `false` without tracking for frames which are not newer than the last tracked frame.
+    /// Their detections have no track ids and should not be published.
+    pub fn update(
+        &mut self,
+        seq: u64,
+        timestamp_ms: u64,
+        detections: &mut [Detection],
+        smoothing: Option<&Smoothing>,
+    ) -> bool {
+        if self.last_seq.is_some_and(|last_seq| seq <= last_seq) {
+            return false;
+        }
+        self.last_seq = Some(seq);
+
+        for track in self.tracks.iter_mut() {
+            track.predicted = track.filter.predict();
+        }
+
+        // Greedily match the pairs of tracks and detections of the same class with the highest IoU
+        let mut candidates: Vec<_> = self
+            .tracks
+            .iter()
+            .enumerate()
+            .flat_map(|(track_index, track)| {
+                detections
+                    .iter()
+                    .enumerate()
+                    .filter(move |(_, detection)| detection.class_id == track.class_id)
+                    .map(move |(detection_index, detection)| {
+                        let overlap = iou(&track.predicted, &detection.bbox);
+                        (track_index, detection_index, overlap)
+                    })
+            })
+            .filter(|(_, _, overlap)| *overlap >= self.config.min_iou)
+            .collect();
+        candidates.sort_by(|a, b| b.2.total_cmp(&a.2));
+
+        let mut track_matched = vec![false; self.tracks.len()];
+        let mut detection_track = vec![None; detections.len()];
+        for (track_index, detection_index, _) in candidates {
+            if track_matched[track_index] || detection_track[detection_index].is_some() {
+                continue;
+            }
+            track_matched[track_index] = true;
+            detection_track[detection_index] = Some(track_index);
+        }
+
+        for (track, matched) in self.tracks.iter_mut().zip(track_matched) {
+            if matched {
+                track.hits += 1;
+                track.misses = 0;
+            } else {
+                track.misses += 1;
+            }
+        }
+
+        for (detection, track_index) in detections.iter_mut().zip(detection_track) {
+            let track = match track_index {
+                Some(track_index) => {
+                    let track = &mut self.tracks[track_index];
+                    track.filter.update(&detection.bbox);
+                    detection.bbox = match smoothing {
+                        Some(smoothing) => {
+                            track
+                                .smoother
+                                .smooth(smoothing, &detection.bbox, timestamp_ms)
+                        }
+                        None => {
+                            track.smoother = BoxSmoother::new(detection.bbox, timestamp_ms);
+                            detection.bbox
+                        }
+                    };
+                    track
+                }
+                None => {
+                    self.next_id += 1;
+                    self.tracks.push(Track {
+                        id: self.next_id,
+                        class_id: detection.class_id,
+                        filter: KalmanBox::new(&detection.bbox),
+                        predicted: detection.bbox,
+                        hits: 1,
+                        misses: 0,
+                        smoother: BoxSmoother::new(detection.bbox, timestamp_ms),
+                    });
+                    self.tracks.last_mut().unwrap()
+                }
+            };
+
+            detection.track_id = (track.hits >= self.config.min_hits).then_some(track.id);
+        }
+
+        let max_misses = self.config.max_misses;
+        self.tracks.retain(|track| track.misses <= max_misses);
+
+        true
+    }
+}
+
+#[cfg(test)]
+mod test {
+
+    use super::*;
+    use crate::smoothing::SmoothingMode;
+
+    fn face(bbox: Bbox) -> Detection {
+        Detection::new(0, "face", 0.9, bbox)
+    }
+
+    fn track_ids(tracker: &mut Tracker, seq: u64, bboxes: &[Bbox]) -> Vec<Option<u64>> {
+        let mut detections: Vec<_> = bboxes.iter().map(|bbox| face(*bbox)).collect();
+        tracker.update(seq, seq * 100, &mut detections, None);
+        detections
+            .iter()
+            .map(|detection| detection.track_id)
+            .collect()
+    }
+
+    #[test]
+    fn test_kalman_box_constant_velocity() {
+        let mut filter = KalmanBox::new(&[0.0, 0.0, 0.2, 0.2]);
+        for step in 1..=10 {
+            filter.predict();
+            let offset = 0.01 * step as f32;
+            filter.update(&[offset, 0.0, 0.2 + offset, 0.2]);
+        }
+
+        // The box keeps moving to the right without a measurement
+        let predicted = filter.predict();
+        assert!((predicted[0] - 0.11).abs() < 0.01, "{predicted:?}");
+        assert!(
+            (predicted[2] - predicted[0] - 0.2).abs() < 0.01,
+            "{predicted:?}"
+        );
+    }
+
+    #[test]
+    fn test_tracker_birth_and_stable_ids() {
+        let mut tracker = Tracker::new(TrackerConfig::new(2, 1, 0.3).unwrap());
+
+        // Tracks are confirmed after two hits and keep their ids while moving
+        assert_eq!(
+            track_ids(
+                &mut tracker,
+                1,
+                &[[0.1, 0.1, 0.3, 0.3], [0.6, 0.6, 0.8, 0.8]]
+            ),
+            vec![None, None]
+        );
+        assert_eq!(
+            track_ids(
+                &mut tracker,
+                2,
+                &[[0.62, 0.6, 0.82, 0.8], [0.12, 0.1, 0.32, 0.3]]
+            ),
+            vec![Some(2), Some(1)]
+        );
+        assert_eq!(
+            track_ids(&mut tracker, 3, &[[0.14, 0.1, 0.34, 0.3]]),
+            vec![Some(1)]
+        );
+    }
+
+    #[test]
+    fn test_tracker_death() {
+        let mut tracker = Tracker::new(TrackerConfig::new(1, 1, 0.3).unwrap());
+        let bbox = [0.1, 0.1, 0.3, 0.3];
+
+        assert_eq!(track_ids(&mut tracker, 1, &[bbox]), vec![Some(1)]);
+
+        // A single miss is tolerated
+        assert_eq!(track_ids(&mut tracker, 2, &[]), vec![]);
+        assert_eq!(track_ids(&mut tracker, 3, &[bbox]), vec![Some(1)]);
+
+        // After two misses, the track is deleted and the object gets a new id
+        assert_eq!(track_ids(&mut tracker, 4, &[]), vec![]);
+        assert_eq!(track_ids(&mut tracker, 5, &[]), vec![]);
+        assert_eq!(track_ids(&mut tracker, 6, &[bbox]), vec![Some(2)]);
+    }
+
+    #[test]
+    fn test_tracker_classes_and_stale_frames() {
+        let mut tracker = Tracker::new(TrackerConfig::new(1, 5, 0.3).unwrap());
+        let bbox = [0.1, 0.1, 0.3, 0.3];
+
+        assert_eq!(track_ids(&mut tracker, 1, &[bbox]), vec![Some(1)]);
+
+        // Overlapping detections of another class start their own track
+        let mut detections = vec![Detection::new(1, "person", 0.9, bbox)];
+        tracker.update(2, 200, &mut detections, None);
+        assert_eq!(detections[0].track_id, Some(2));
+
+        // Frames older than the last tracked one are not tracked
+        let mut detections = vec![face(bbox)];
+        assert!(!tracker.update(2, 200, &mut detections, None));
+        assert_eq!(detections[0].track_id, None);
+    }
+
+    #[test]
+    fn test_tracker_smoothing() {
+        let mut tracker = Tracker::new(TrackerConfig::new(1, 5, 0.3).unwrap());
+        let smoothing = Smoothing::new(SmoothingMode::Ema, 0.5).unwrap();
+
+        // The first box of a track is kept, matched boxes are smoothed
+        let mut detections = vec![face([0.0, 0.0, 0.5, 0.5])];
+        tracker.update(1, 100, &mut detections, Some(&smoothing));
+        assert_eq!(detections[0].bbox, [0.0, 0.0, 0.5, 0.5]);
+
+        let mut detections = vec![face([0.125, 0.0, 0.625, 0.5])];
+        tracker.update(2, 200, &mut detections, Some(&smoothing));
+        assert_eq!(detections[0].bbox, [0.0625, 0.0, 0.5625, 0.5]);
+
+        // Without smoothing, the measured boxes are passed through
+        let mut detections = vec![face([0.125, 0.0, 0.625, 0.5])];
+        tracker.update(3, 300, &mut detections, None);
+        assert_eq!(detections[0].bbox, [0.125, 0.0, 0.625, 0.5]);
+    }
+
+    #[test]
+    fn test_tracker_config_validation() {
+        assert!(TrackerConfig::new(0, 1, 0.3).is_err());
+        assert!(TrackerConfig::new(1, 1, 1.3).is_err());
+        assert!(TrackerConfig::new(3, 0, 0.0).is_ok());
+    }
+}
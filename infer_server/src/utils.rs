@@ -1,21 +1,330 @@
 //! Utility functions
 //!
-use std::{fs::File, io::Cursor};
+use std::{collections::HashMap, fs::File, io::Write, path::Path, time::Duration};
 
-use anyhow::Result;
+use anyhow::{bail, Context, Result};
 use reqwest::Client;
+use serde::de::DeserializeOwned;
+use sha2::{Digest, Sha256};
+use tempfile::NamedTempFile;
+
+/// Number of attempts to download a file before giving up.
+const DOWNLOAD_ATTEMPTS: u32 = 4;
+
+/// Delay before the first retry of a failed download, doubled for every further retry.
+const DOWNLOAD_BACKOFF: Duration = Duration::from_millis(500);
 
 /// Download a file from a URL to a given filepath.
+///
+/// Failed downloads are retried with exponential backoff, except for client errors like `404`.
 pub async fn download_file(
     client: &Client,
     url: &str,
     filepath: impl AsRef<std::path::Path>,
 ) -> Result<()> {
-    let resp = client.get(url).send().await?;
+    download_file_with_retries(client, url, filepath, DOWNLOAD_ATTEMPTS, DOWNLOAD_BACKOFF).await
+}
+
+/// Download a file from a URL to a given filepath with up to `attempts` tries.
+pub async fn download_file_with_retries(
+    client: &Client,
+    url: &str,
+    filepath: impl AsRef<std::path::Path>,
+    attempts: u32,
+    backoff: Duration,
+) -> Result<()> {
+    let filepath = filepath.as_ref();
+    let mut delay = backoff;
+
+    for attempt in 1.. {
+        match try_download_file(client, url, filepath).await {
+            Ok(()) => return Ok(()),
+            Err(err) if attempt < attempts && is_retryable(&err) => {
+                log::warn!("Download of {url} failed (attempt {attempt}/{attempts}): {err:#}");
+                tokio::time::sleep(delay).await;
+                delay *= 2;
+            }
+            Err(err) => {
+                return Err(
+                    err.context(format!("failed to download {url} after {attempt} attempts"))
+                )
+            }
+        }
+    }
 
-    let mut file = File::create(filepath)?;
-    let mut content = Cursor::new(resp.bytes().await?);
-    std::io::copy(&mut content, &mut file)?;
+    unreachable!("download loop only ends by returning")
+}
+
+/// Download a file once.
+///
+/// The data is written to a temporary file next to `filepath`, which is only renamed to
+/// `filepath` after the complete response was received. An interrupted download therefore never
+/// leaves a partial file at `filepath`, and the temporary file is removed on errors.
+async fn try_download_file(client: &Client, url: &str, filepath: &Path) -> Result<()> {
+    let mut resp = client.get(url).send().await?.error_for_status()?;
+    let expected_len = resp.content_length();
+
+    let dir = match filepath.parent() {
+        Some(dir) if !dir.as_os_str().is_empty() => dir,
+        _ => Path::new("."),
+    };
+    let mut file = NamedTempFile::new_in(dir)?;
+
+    let mut len = 0;
+    while let Some(chunk) = resp.chunk().await? {
+        file.write_all(&chunk)?;
+        len += chunk.len() as u64;
+    }
+
+    if let Some(expected_len) = expected_len {
+        if len != expected_len {
+            bail!("incomplete download: received {len} of {expected_len} bytes");
+        }
+    }
+
+    file.as_file().sync_all()?;
+    file.persist(filepath)
+        .with_context(|| format!("failed to move download to {}", filepath.display()))?;
 
     Ok(())
 }
+
+/// Check if retrying a failed download can succeed.
+fn is_retryable(err: &anyhow::Error) -> bool {
+    match err.downcast_ref::<reqwest::Error>() {
+        Some(err) => !err.status().is_some_and(|status| status.is_client_error()),
+        None => true,
+    }
+}
+
+/// Calculate the SHA-256 checksum of a file as lowercase hex string.
+pub fn sha256_file(filepath: impl AsRef<Path>) -> Result<String> {
+    let mut file = File::open(filepath)?;
+    let mut hasher = Sha256::new();
+    std::io::copy(&mut file, &mut hasher)?;
+
+    Ok(format!("{:x}", hasher.finalize()))
+}
+
+/// Check that the SHA-256 checksum of a file matches the expected hex string.
+pub fn verify_sha256(filepath: impl AsRef<Path>, expected: &str) -> Result<()> {
+    let filepath = filepath.as_ref();
+    let actual = sha256_file(filepath)?;
+    if !actual.eq_ignore_ascii_case(expected.trim()) {
+        bail!(
+            "checksum mismatch for {}: expected SHA-256 {}, got {}",
+            filepath.display(),
+            expected.trim(),
+            actual
+        );
+    }
+
+    Ok(())
+}
+
+/// Read a TOML file with a table of settings per stream name, validating the settings of each.
+///
+/// `what` names the settings in error messages, e.g. `zones`.
+pub fn read_stream_configs<T: DeserializeOwned>(
+    path: impl AsRef<Path>,
+    what: &str,
+    validate: impl Fn(&T) -> Result<()>,
+) -> Result<HashMap<String, T>> {
+    let path = path.as_ref();
+    let content = std::fs::read_to_string(path)
+        .with_context(|| format!("failed to read {what} {}", path.display()))?;
+    let streams: HashMap<String, T> =
+        toml::from_str(&content).with_context(|| format!("invalid {what} {}", path.display()))?;
+
+    for (name, config) in streams.iter() {
+        validate(config).with_context(|| format!("invalid {what} of stream {name}"))?;
+    }
+
+    Ok(streams)
+}
+
+#[cfg(test)]
+mod test {
+
+    use std::{
+        net::SocketAddr,
+        sync::{
+            atomic::{AtomicUsize, Ordering},
+            Arc,
+        },
+    };
+
+    use axum::{http::StatusCode, routing::get, Extension, Router};
+    use serde::Deserialize;
+    use tokio::{io::AsyncWriteExt, net::TcpListener};
+
+    use super::*;
+
+    const BACKOFF: Duration = Duration::from_millis(10);
+
+    #[derive(Debug, Deserialize, PartialEq)]
+    struct Config {
+        value: u32,
+    }
+
+    fn validate_config(config: &Config) -> Result<()> {
+        if config.value == 0 {
+            bail!("value has to be positive");
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn test_read_stream_configs() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        let path = dir.path().join("configs.toml");
+        std::fs::write(&path, "[hallway]\nvalue = 1\n\n[lobby]\nvalue = 2\n")?;
+
+        let streams = read_stream_configs(&path, "configs", validate_config)?;
+        assert_eq!(streams.len(), 2);
+        assert_eq!(streams["lobby"], Config { value: 2 });
+
+        // Errors name the settings, the file or the stream
+        std::fs::write(&path, "[lobby]\nvalue = 0\n")?;
+        let err = read_stream_configs(&path, "configs", validate_config).unwrap_err();
+        assert_eq!(err.to_string(), "invalid configs of stream lobby");
+
+        std::fs::write(&path, "[lobby]\nvalue = \"one\"\n")?;
+        let err = read_stream_configs(&path, "configs", validate_config).unwrap_err();
+        assert!(err.to_string().starts_with("invalid configs "));
+
+        let missing = dir.path().join("missing.toml");
+        assert!(read_stream_configs(&missing, "configs", validate_config).is_err());
+
+        Ok(())
+    }
+
+    /// Serve a stand-in for a model download server on a random local port.
+    fn spawn_file_server() -> (SocketAddr, Arc<AtomicUsize>) {
+        let flaky_requests = Arc::new(AtomicUsize::new(0));
+
+        async fn flaky(
+            Extension(flaky_requests): Extension<Arc<AtomicUsize>>,
+        ) -> Result<&'static str, StatusCode> {
+            match flaky_requests.fetch_add(1, Ordering::SeqCst) {
+                0 | 1 => Err(StatusCode::SERVICE_UNAVAILABLE),
+                _ => Ok("model data"),
+            }
+        }
+
+        let app = Router::new()
+            .route("/model.onnx", get(|| async { "model data" }))
+            .route("/flaky.onnx", get(flaky))
+            .layer(Extension(flaky_requests.clone()));
+
+        let server =
+            axum::Server::bind(&"127.0.0.1:0".parse().unwrap()).serve(app.into_make_service());
+        let addr = server.local_addr();
+        tokio::spawn(server);
+
+        (addr, flaky_requests)
+    }
+
+    /// Serve a response which announces more data than it sends.
+    async fn spawn_truncating_server() -> SocketAddr {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+
+        tokio::spawn(async move {
+            while let Ok((mut stream, _)) = listener.accept().await {
+                let mut buf = [0; 1024];
+                let _ = tokio::io::AsyncReadExt::read(&mut stream, &mut buf).await;
+                let _ = stream
+                    .write_all(b"HTTP/1.1 200 OK\r\nContent-Length: 100\r\n\r\npartial")
+                    .await;
+            }
+        });
+
+        addr
+    }
+
+    fn dir_entries(dir: &Path) -> usize {
+        std::fs::read_dir(dir).unwrap().count()
+    }
+
+    #[tokio::test]
+    async fn test_download_file() -> Result<()> {
+        let (addr, _) = spawn_file_server();
+        let dir = tempfile::tempdir()?;
+        let filepath = dir.path().join("model.onnx");
+
+        let url = format!("http://{addr}/model.onnx");
+        download_file_with_retries(&Client::new(), &url, &filepath, 1, BACKOFF).await?;
+
+        assert_eq!(std::fs::read(&filepath)?, b"model data");
+        assert_eq!(dir_entries(dir.path()), 1);
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_download_file_not_found() -> Result<()> {
+        let (addr, _) = spawn_file_server();
+        let dir = tempfile::tempdir()?;
+        let filepath = dir.path().join("model.onnx");
+
+        let url = format!("http://{addr}/missing.onnx");
+        let err = download_file_with_retries(&Client::new(), &url, &filepath, 3, BACKOFF)
+            .await
+            .unwrap_err();
+
+        // Client errors are not retried
+        assert!(err.to_string().contains("after 1 attempts"));
+        assert_eq!(dir_entries(dir.path()), 0);
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_download_file_retries() -> Result<()> {
+        let (addr, flaky_requests) = spawn_file_server();
+        let dir = tempfile::tempdir()?;
+        let filepath = dir.path().join("model.onnx");
+
+        let url = format!("http://{addr}/flaky.onnx");
+        download_file_with_retries(&Client::new(), &url, &filepath, 3, BACKOFF).await?;
+
+        assert_eq!(flaky_requests.load(Ordering::SeqCst), 3);
+        assert_eq!(std::fs::read(&filepath)?, b"model data");
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_download_file_truncated() -> Result<()> {
+        let addr = spawn_truncating_server().await;
+        let dir = tempfile::tempdir()?;
+        let filepath = dir.path().join("model.onnx");
+
+        let url = format!("http://{addr}/model.onnx");
+        assert!(
+            download_file_with_retries(&Client::new(), &url, &filepath, 2, BACKOFF)
+                .await
+                .is_err()
+        );
+
+        // No partial or temporary files are left behind
+        assert_eq!(dir_entries(dir.path()), 0);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_sha256_file() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        let filepath = dir.path().join("data.bin");
+        std::fs::write(&filepath, b"abc")?;
+
+        let expected = "ba7816bf8f01cfea414140de5dae2223b00361a396177a9cb410ff61f20015ad";
+        assert_eq!(sha256_file(&filepath)?, expected);
+        verify_sha256(&filepath, &expected.to_uppercase())?;
+        assert!(verify_sha256(&filepath, &expected.replace('b', "c")).is_err());
+
+        Ok(())
+    }
+}
@@ -0,0 +1,478 @@
+//! Webhooks notifying about objects appearing in and disappearing from streams.
+//!
+//! A stream is considered occupied while it has at least one detection. Changes are debounced,
+//! so that single missed or spurious detections do not cause a storm of events.
+use std::{collections::HashMap, path::Path, time::Duration};
+
+use anyhow::{bail, Context, Result};
+use base64::{engine::general_purpose::STANDARD as BASE64, Engine};
+use reqwest::{header, Client, Url};
+use serde::{Deserialize, Serialize};
+use tokio::{sync::broadcast::error::RecvError, task::JoinHandle};
+
+use crate::{
+    detections::{DetectionOutput, FrameDetections},
+    utils::read_stream_configs,
+    DetectionsReceiver,
+};
+
+/// Webhooks of a single stream.
+#[derive(Clone, Debug, Deserialize, PartialEq)]
+pub struct WebhookConfig {
+    /// URLs to POST the events to.
+    pub urls: Vec<String>,
+    /// Time in milliseconds a change has to last before an event is sent.
+    #[serde(default = "default_min_duration_ms")]
+    pub min_duration_ms: u64,
+    /// Minimum time in milliseconds between two events of the stream.
+    #[serde(default = "default_debounce_ms")]
+    pub debounce_ms: u64,
+    /// Attach the JPEG frame as base64 string to the events.
+    #[serde(default)]
+    pub snapshot: bool,
+    /// Number of retries of a failed delivery.
+    #[serde(default = "default_max_retries")]
+    pub max_retries: u32,
+    /// Delay in milliseconds before the first retry, doubled for every further retry.
+    #[serde(default = "default_retry_backoff_ms")]
+    pub retry_backoff_ms: u64,
+    /// Time in milliseconds after which a delivery attempt is aborted.
+    #[serde(default = "default_timeout_ms")]
+    pub timeout_ms: u64,
+}
+
+fn default_min_duration_ms() -> u64 {
+    1000
+}
+
+fn default_debounce_ms() -> u64 {
+    5000
+}
+
+fn default_max_retries() -> u32 {
+    3
+}
+
+fn default_retry_backoff_ms() -> u64 {
+    500
+}
+
+fn default_timeout_ms() -> u64 {
+    10_000
+}
+
+impl WebhookConfig {
+    pub fn validate(&self) -> Result<()> {
+        if self.urls.is_empty() {
+            bail!("at least one webhook URL is required");
+        }
+        if self.timeout_ms == 0 {
+            bail!("webhook timeout has to be positive");
+        }
+        for url in self.urls.iter() {
+            Url::parse(url).with_context(|| format!("invalid webhook URL {url}"))?;
+        }
+
+        Ok(())
+    }
+}
+
+/// Read the webhooks of several streams by name from a TOML file.
+///
+/// ```toml
+/// [hallway]
+/// urls = ["http://127.0.0.1:8080/events"]
+/// min_duration_ms = 1000
+/// debounce_ms = 5000
+/// snapshot = true
+/// ```
+pub fn read_webhooks_file(path: impl AsRef<Path>) -> Result<HashMap<String, WebhookConfig>> {
+    read_stream_configs(path, "webhooks", WebhookConfig::validate)
+}
+
+/// Change of the occupancy of a stream.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum PresenceEvent {
+    /// Objects appeared in the empty stream.
+    Appeared,
+    /// All objects disappeared from the stream.
+    Disappeared,
+}
+
+/// Debouncer of the presence of objects in a stream.
+#[derive(Debug)]
+pub struct PresenceDebouncer {
+    min_duration_ms: u64,
+    debounce_ms: u64,
+    /// Presence reported with the last event.
+    present: bool,
+    /// Time since when the presence differs from the reported one.
+    changed_since_ms: Option<u64>,
+    last_event_ms: Option<u64>,
+}
+
+impl PresenceDebouncer {
+    pub fn new(min_duration_ms: u64, debounce_ms: u64) -> Self {
+        Self {
+            min_duration_ms,
+            debounce_ms,
+            present: false,
+            changed_since_ms: None,
+            last_event_ms: None,
+        }
+    }
+
+    /// Update with the presence in the frame at `timestamp_ms`, returning an event to send.
+    pub fn update(&mut self, timestamp_ms: u64, present: bool) -> Option<PresenceEvent> {
+        if present == self.present {
+            self.changed_since_ms = None;
+            return None;
+        }
+
+        let changed_since_ms = *self.changed_since_ms.get_or_insert(timestamp_ms);
+        let lasted = timestamp_ms.saturating_sub(changed_since_ms) >= self.min_duration_ms;
+        let debounced = self
+            .last_event_ms
+            .is_none_or(|last| timestamp_ms.saturating_sub(last) >= self.debounce_ms);
+        if !(lasted && debounced) {
+            return None;
+        }
+
+        self.present = present;
+        self.changed_since_ms = None;
+        self.last_event_ms = Some(timestamp_ms);
+
+        Some(if present {
+            PresenceEvent::Appeared
+        } else {
+            PresenceEvent::Disappeared
+        })
+    }
+}
+
+/// Event POSTed to the webhooks.
+#[derive(Clone, Debug, Serialize)]
+pub struct WebhookEvent {
+    pub event: PresenceEvent,
+    pub stream: String,
+    /// Sequence number of the frame in its stream.
+    pub seq: u64,
+    /// Time of receiving the frame in milliseconds since the UNIX epoch.
+    pub timestamp_ms: u64,
+    pub detections: Vec<DetectionOutput>,
+    /// Base64 encoded JPEG data of the frame.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub snapshot: Option<String>,
+}
+
+impl WebhookEvent {
+    pub fn new(event: PresenceEvent, stream: &str, frame: FrameDetections, snapshot: bool) -> Self {
+        Self {
+            event,
+            stream: stream.to_owned(),
+            seq: frame.seq,
+            timestamp_ms: frame.timestamp_ms,
+            snapshot: snapshot.then(|| BASE64.encode(&frame.jpeg)),
+            detections: frame.detections,
+        }
+    }
+}
+
+/// Build an HTTP client aborting requests after the timeout, so that hanging receivers do not
+/// keep deliveries alive forever.
+pub fn webhook_client(timeout: Duration) -> Result<Client> {
+    Ok(Client::builder().timeout(timeout).build()?)
+}
+
+/// Send events to the webhooks of a stream for as long as its detections are published.
+///
+/// The receiver keeps the stream infered even if nobody else watches it.
+pub fn spawn_webhooks(
+    name: String,
+    config: WebhookConfig,
+    mut rx: DetectionsReceiver,
+) -> Result<JoinHandle<()>> {
+    let client = webhook_client(Duration::from_millis(config.timeout_ms))?;
+
+    Ok(tokio::spawn(async move {
+        let mut debouncer = PresenceDebouncer::new(config.min_duration_ms, config.debounce_ms);
+        let backoff = Duration::from_millis(config.retry_backoff_ms);
+
+        loop {
+            let frame = match rx.recv().await {
+                Ok(frame) => frame,
+                Err(RecvError::Lagged(_)) => continue,
+                Err(RecvError::Closed) => break,
+            };
+
+            let present = !frame.detections.is_empty();
+            let Some(event) = debouncer.update(frame.timestamp_ms, present) else {
+                continue;
+            };
+            log::info!("Objects {event:?} in stream {name}");
+
+            let event = WebhookEvent::new(event, &name, frame, config.snapshot);
+            let body = match serde_json::to_vec(&event) {
+                Ok(body) => body,
+                Err(err) => {
+                    log::warn!("Failed to serialize webhook event: {err}");
+                    continue;
+                }
+            };
+
+            // Deliver in the background, so that a slow receiver does not delay later events
+            for url in config.urls.iter() {
+                let (client, url, body) = (client.clone(), url.clone(), body.clone());
+                let attempts = config.max_retries + 1;
+                tokio::spawn(async move {
+                    if let Err(err) = deliver(&client, &url, body, attempts, backoff).await {
+                        log::warn!("{err:#}");
+                    }
+                });
+            }
+        }
+    }))
+}
+
+/// POST a JSON body to a URL with up to `attempts` tries.
+///
+/// Failed deliveries are retried with exponential backoff, except for client errors like `404`.
+pub async fn deliver(
+    client: &Client,
+    url: &str,
+    body: Vec<u8>,
+    attempts: u32,
+    backoff: Duration,
+) -> Result<()> {
+    let mut delay = backoff;
+
+    for attempt in 1.. {
+        let result = client
+            .post(url)
+            .header(header::CONTENT_TYPE, "application/json")
+            .body(body.clone())
+            .send()
+            .await
+            .and_then(|resp| resp.error_for_status());
+
+        match result {
+            Ok(_) => return Ok(()),
+            Err(err)
+                if attempt < attempts
+                    && !err.status().is_some_and(|status| status.is_client_error()) =>
+            {
+                log::warn!("Webhook {url} failed (attempt {attempt}/{attempts}): {err}");
+                tokio::time::sleep(delay).await;
+                delay *= 2;
+            }
+            Err(err) => {
+                let plural = if attempt == 1 { "" } else { "s" };
+                return Err(anyhow::Error::new(err).context(format!(
+                    "failed to deliver webhook to {url} after {attempt} attempt{plural}"
+                )));
+            }
+        }
+    }
+
+    unreachable!("delivery loop only ends by returning")
+}
+
+#[cfg(test)]
+mod test {
+
+    use std::{
+        net::SocketAddr,
+        sync::{
+            atomic::{AtomicUsize, Ordering},
+            Arc, Mutex,
+        },
+    };
+
+    use axum::{http::StatusCode, routing::post, Extension, Json, Router};
+    use bytes::Bytes;
+    use serde_json::Value;
+
+    use super::*;
+    use crate::{broadcast_channel, nn::Detection};
+
+    const BACKOFF: Duration = Duration::from_millis(10);
+
+    /// Events received by the stand-in and the number of requests to fail first.
+    #[derive(Default)]
+    struct Receiver {
+        events: Mutex<Vec<Value>>,
+        failures: AtomicUsize,
+    }
+
+    /// Serve a stand-in for a webhook receiver on a random local port.
+    fn spawn_receiver(failures: usize) -> (SocketAddr, Arc<Receiver>) {
+        let receiver = Arc::new(Receiver {
+            failures: AtomicUsize::new(failures),
+            ..Default::default()
+        });
+
+        async fn receive(
+            Extension(receiver): Extension<Arc<Receiver>>,
+            Json(event): Json<Value>,
+        ) -> StatusCode {
+            let failures = &receiver.failures;
+            if failures
+                .fetch_update(Ordering::SeqCst, Ordering::SeqCst, |n| n.checked_sub(1))
+                .is_ok()
+            {
+                return StatusCode::SERVICE_UNAVAILABLE;
+            }
+
+            receiver.events.lock().unwrap().push(event);
+            StatusCode::NO_CONTENT
+        }
+
+        let app = Router::new()
+            .route("/events", post(receive))
+            .route("/gone", post(|| async { StatusCode::GONE }))
+            .route("/hang", post(futures::future::pending::<StatusCode>))
+            .layer(Extension(receiver.clone()));
+
+        let server =
+            axum::Server::bind(&"127.0.0.1:0".parse().unwrap()).serve(app.into_make_service());
+        let addr = server.local_addr();
+        tokio::spawn(server);
+
+        (addr, receiver)
+    }
+
+    fn frame(seq: u64, timestamp_ms: u64, faces: usize) -> FrameDetections {
+        let detections = vec![Detection::new(0, "face", 0.9, [0.25, 0.25, 0.5, 0.5]); faces];
+        FrameDetections::new(
+            seq,
+            timestamp_ms,
+            640,
+            480,
+            &detections,
+            Bytes::from_static(b"jpeg"),
+        )
+    }
+
+    #[test]
+    fn test_debounce_flicker() {
+        let mut debouncer = PresenceDebouncer::new(500, 2000);
+
+        // A single spurious detection does not trigger an event
+        assert_eq!(debouncer.update(0, true), None);
+        assert_eq!(debouncer.update(100, false), None);
+
+        // A face which stays for the minimum duration does
+        assert_eq!(debouncer.update(200, true), None);
+        assert_eq!(debouncer.update(600, true), None);
+        assert_eq!(debouncer.update(700, true), Some(PresenceEvent::Appeared));
+
+        // Leaving right away is only reported after the debounce time
+        assert_eq!(debouncer.update(800, false), None);
+        assert_eq!(debouncer.update(1500, false), None);
+        assert_eq!(
+            debouncer.update(2700, false),
+            Some(PresenceEvent::Disappeared)
+        );
+        assert_eq!(debouncer.update(2800, false), None);
+    }
+
+    #[tokio::test]
+    async fn test_deliver_retries() -> Result<()> {
+        let (addr, receiver) = spawn_receiver(2);
+
+        let url = format!("http://{addr}/events");
+        deliver(&Client::new(), &url, b"{}".to_vec(), 3, BACKOFF).await?;
+
+        assert_eq!(receiver.events.lock().unwrap().len(), 1);
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_deliver_gives_up() {
+        let (addr, receiver) = spawn_receiver(5);
+
+        // Server errors are retried until the attempts are used up
+        let url = format!("http://{addr}/events");
+        let err = deliver(&Client::new(), &url, b"{}".to_vec(), 3, BACKOFF)
+            .await
+            .unwrap_err();
+        assert!(err.to_string().contains("after 3 attempts"));
+        assert_eq!(receiver.failures.load(Ordering::SeqCst), 2);
+
+        // Client errors are not retried
+        let url = format!("http://{addr}/gone");
+        let err = deliver(&Client::new(), &url, b"{}".to_vec(), 3, BACKOFF)
+            .await
+            .unwrap_err();
+        assert!(err.to_string().contains("after 1 attempt"));
+        assert!(!err.to_string().contains("attempts"));
+    }
+
+    #[tokio::test]
+    async fn test_deliver_times_out() -> Result<()> {
+        let (addr, receiver) = spawn_receiver(0);
+
+        // The receiver never answers, every attempt is aborted
+        let url = format!("http://{addr}/hang");
+        let client = webhook_client(Duration::from_millis(50))?;
+        let start = std::time::Instant::now();
+        assert!(deliver(&client, &url, b"{}".to_vec(), 2, BACKOFF)
+            .await
+            .is_err());
+        assert!(start.elapsed() < Duration::from_secs(5));
+        assert!(receiver.events.lock().unwrap().is_empty());
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_webhook_events() {
+        let (addr, receiver) = spawn_receiver(1);
+        let config = WebhookConfig {
+            urls: vec![format!("http://{addr}/events")],
+            min_duration_ms: 0,
+            debounce_ms: 0,
+            snapshot: true,
+            max_retries: 2,
+            retry_backoff_ms: 10,
+            timeout_ms: 1000,
+        };
+
+        let (detections_tx, detections_rx) = broadcast_channel();
+        spawn_webhooks("lobby".to_owned(), config, detections_rx).unwrap();
+        detections_tx.send(frame(1, 100, 0)).unwrap();
+        detections_tx.send(frame(2, 200, 2)).unwrap();
+
+        // The first delivery fails and is retried
+        for _ in 0..100 {
+            if !receiver.events.lock().unwrap().is_empty() {
+                break;
+            }
+            tokio::time::sleep(BACKOFF).await;
+        }
+
+        let events = receiver.events.lock().unwrap();
+        assert_eq!(events.len(), 1);
+        assert_eq!(events[0]["event"], "appeared");
+        assert_eq!(events[0]["stream"], "lobby");
+        assert_eq!(events[0]["seq"], 2);
+        assert_eq!(events[0]["detections"].as_array().unwrap().len(), 2);
+        assert_eq!(events[0]["snapshot"], BASE64.encode(b"jpeg"));
+    }
+
+    #[test]
+    fn test_webhook_config_defaults() -> Result<()> {
+        let config: WebhookConfig = toml::from_str("urls = [\"http://127.0.0.1:8080/events\"]")?;
+        config.validate()?;
+        assert_eq!(config.debounce_ms, 5000);
+        assert!(!config.snapshot);
+        assert_eq!(config.timeout_ms, 10_000);
+
+        let config: WebhookConfig = toml::from_str("urls = [\"not a url\"]")?;
+        assert!(config.validate().is_err());
+
+        Ok(())
+    }
+}
@@ -0,0 +1,316 @@
+//! Regions of interest of streams, restricting and labeling detections.
+//!
+//! Zones are polygons in **relative** coordinates. A detection falls into the first zone which
+//! contains the center of its bounding box. Virtual lines count the objects crossing them.
+use std::{collections::HashMap, path::Path};
+
+use anyhow::{bail, Result};
+use image::{Rgb, RgbImage};
+use imageproc::{drawing::draw_antialiased_line_segment_mut, pixelops::interpolate};
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    nn::{Detection, Point},
+    utils::read_stream_configs,
+};
+
+/// Color of the zone outlines.
+const ZONE_COLOR: [u8; 3] = [255, 255, 255];
+/// Opacity of the zone outlines, so that they do not distract from the detections.
+const ZONE_OPACITY: f32 = 0.35;
+
+/// Named polygon in relative coordinates.
+#[derive(Clone, Debug, Deserialize, PartialEq, Serialize)]
+pub struct Zone {
+    pub name: String,
+    /// Corners of the polygon, at least three.
+    pub polygon: Vec<Point>,
+}
+
+impl Zone {
+    /// Check whether the point is inside the polygon with the even-odd rule.
+    pub fn contains(&self, point: Point) -> bool {
+        let [x, y] = point;
+        let mut inside = false;
+
+        let corners = self.polygon.iter();
+        let previous_corners = self.polygon.iter().cycle().skip(self.polygon.len() - 1);
+        for (a, b) in corners.zip(previous_corners) {
+            if (a[1] > y) != (b[1] > y) && x < (b[0] - a[0]) * (y - a[1]) / (b[1] - a[1]) + a[0] {
+                inside = !inside;
+            }
+        }
+
+        inside
+    }
+}
+
+/// Named virtual line segment in relative coordinates.
+#[derive(Clone, Debug, Deserialize, PartialEq, Serialize)]
+pub struct CountingLine {
+    pub name: String,
+    pub start: Point,
+    pub end: Point,
+}
+
+impl CountingLine {
+    /// Check whether the movement from `from` to `to` crosses the line segment.
+    ///
+    /// Returns `Some(true)` when crossing from the left to the right side of the line,
+    /// `Some(false)` the other way round and `None` without crossing.
+    pub fn crossing(&self, from: Point, to: Point) -> Option<bool> {
+        let side_from = side(self.start, self.end, from);
+        let side_to = side(self.start, self.end, to);
+        if (side_from < 0.0) == (side_to < 0.0) || side_from == 0.0 {
+            return None;
+        }
+
+        // The line has to be crossed within the segment, not on its extension
+        if (side(from, to, self.start) < 0.0) == (side(from, to, self.end) < 0.0) {
+            return None;
+        }
+
+        Some(side_to > 0.0)
+    }
+}
+
+/// Side of the point when looking from `start` to `end`, positive on the right.
+///
+/// In image coordinates with the y axis pointing down, a line from left to right has its right
+/// side below it.
+fn side(start: Point, end: Point, point: Point) -> f32 {
+    let direction = [end[0] - start[0], end[1] - start[1]];
+    direction[0] * (point[1] - start[1]) - direction[1] * (point[0] - start[0])
+}
+
+/// Zones and counting lines of a single stream.
+#[derive(Clone, Debug, Default, Deserialize, PartialEq, Serialize)]
+pub struct StreamZones {
+    #[serde(default)]
+    pub zones: Vec<Zone>,
+    /// Drop detections outside of all zones instead of only labeling the ones inside.
+    #[serde(default)]
+    pub filter: bool,
+    #[serde(default)]
+    pub lines: Vec<CountingLine>,
+}
+
+impl StreamZones {
+    /// Whether neither zones nor lines are configured.
+    pub fn is_empty(&self) -> bool {
+        self.zones.is_empty() && self.lines.is_empty()
+    }
+
+    pub fn validate(&self) -> Result<()> {
+        for line in self.lines.iter() {
+            if line.name.is_empty() {
+                bail!("line names must not be empty");
+            }
+            if line.start == line.end {
+                bail!("line {} needs distinct start and end points", line.name);
+            }
+        }
+
+        for zone in self.zones.iter() {
+            if zone.name.is_empty() {
+                bail!("zone names must not be empty");
+            }
+            if zone.polygon.len() < 3 {
+                bail!(
+                    "zone {} needs at least 3 corners, got {}",
+                    zone.name,
+                    zone.polygon.len()
+                );
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Label detections with the zone they fall in, dropping the ones outside if filtering.
+    pub fn apply(&self, detections: &mut Vec<Detection>) {
+        for detection in detections.iter_mut() {
+            let bbox = detection.bbox;
+            let center = [(bbox[0] + bbox[2]) / 2.0, (bbox[1] + bbox[3]) / 2.0];
+            detection.zone = self
+                .zones
+                .iter()
+                .find(|zone| zone.contains(center))
+                .map(|zone| zone.name.clone());
+        }
+
+        if self.filter {
+            detections.retain(|detection| detection.zone.is_some());
+        }
+    }
+}
+
+/// Read the zones of several streams by name from a TOML file.
+///
+/// ```toml
+/// [hallway]
+/// filter = true
+///
+/// [[hallway.zones]]
+/// name = "door"
+/// polygon = [[0.1, 0.2], [0.4, 0.2], [0.4, 0.9], [0.1, 0.9]]
+///
+/// [[hallway.lines]]
+/// name = "entrance"
+/// start = [0.5, 0.0]
+/// end = [0.5, 1.0]
+/// ```
+pub fn read_zones_file(path: impl AsRef<Path>) -> Result<HashMap<String, StreamZones>> {
+    read_stream_configs(path, "zones", StreamZones::validate)
+}
+
+/// Draw the outlines of the zones and the counting lines faintly on the image.
+pub(crate) fn draw_zones_on_image(frame: &mut RgbImage, zones: &StreamZones) {
+    let (width, height) = (frame.width() as f32, frame.height() as f32);
+    let to_px = |point: &Point| ((point[0] * width) as i32, (point[1] * height) as i32);
+
+    let zone_edges = zones.zones.iter().flat_map(|zone| {
+        let previous_corners = zone.polygon.iter().cycle().skip(zone.polygon.len() - 1);
+        previous_corners.zip(zone.polygon.iter())
+    });
+    let lines = zones.lines.iter().map(|line| (&line.start, &line.end));
+
+    for (start, end) in zone_edges.chain(lines) {
+        draw_antialiased_line_segment_mut(
+            frame,
+            to_px(start),
+            to_px(end),
+            Rgb(ZONE_COLOR),
+            |line, original, weight| interpolate(line, original, weight * ZONE_OPACITY),
+        );
+    }
+}
+
+#[cfg(test)]
+mod test {
+
+    use super::*;
+
+    fn door() -> Zone {
+        Zone {
+            name: "door".to_owned(),
+            polygon: vec![[0.0, 0.0], [0.5, 0.0], [0.5, 1.0], [0.0, 1.0]],
+        }
+    }
+
+    fn face(bbox: [f32; 4]) -> Detection {
+        Detection::new(0, "face", 0.9, bbox)
+    }
+
+    #[test]
+    fn test_zone_contains() {
+        let triangle = Zone {
+            name: "triangle".to_owned(),
+            polygon: vec![[0.0, 0.0], [1.0, 0.0], [0.0, 1.0]],
+        };
+        assert!(triangle.contains([0.25, 0.25]));
+        assert!(!triangle.contains([0.75, 0.75]));
+        assert!(!triangle.contains([-0.25, 0.25]));
+    }
+
+    #[test]
+    fn test_label_and_filter() {
+        let mut zones = StreamZones {
+            zones: vec![door()],
+            filter: false,
+            ..Default::default()
+        };
+        let detections = vec![face([0.1, 0.1, 0.3, 0.3]), face([0.6, 0.1, 0.9, 0.3])];
+
+        let mut labeled = detections.clone();
+        zones.apply(&mut labeled);
+        assert_eq!(labeled[0].zone.as_deref(), Some("door"));
+        assert_eq!(labeled[1].zone, None);
+
+        zones.filter = true;
+        let mut filtered = detections;
+        zones.apply(&mut filtered);
+        assert_eq!(filtered.len(), 1);
+        assert_eq!(filtered[0].zone.as_deref(), Some("door"));
+    }
+
+    #[test]
+    fn test_line_crossing() {
+        let line = CountingLine {
+            name: "entrance".to_owned(),
+            start: [0.5, 0.0],
+            end: [0.5, 0.5],
+        };
+
+        // Looking down the line, the right side is on the left of the image
+        assert_eq!(line.crossing([0.6, 0.25], [0.4, 0.25]), Some(true));
+        assert_eq!(line.crossing([0.4, 0.25], [0.6, 0.25]), Some(false));
+        assert_eq!(line.crossing([0.4, 0.25], [0.45, 0.25]), None);
+
+        // Passing below the end of the segment is no crossing
+        assert_eq!(line.crossing([0.4, 0.75], [0.6, 0.75]), None);
+    }
+
+    #[test]
+    fn test_zones_validation() {
+        let mut zones = StreamZones {
+            zones: vec![door()],
+            filter: true,
+            ..Default::default()
+        };
+        assert!(zones.validate().is_ok());
+
+        zones.zones[0].polygon.truncate(2);
+        assert!(zones.validate().is_err());
+
+        let zones = StreamZones {
+            lines: vec![CountingLine {
+                name: "entrance".to_owned(),
+                start: [0.5, 0.0],
+                end: [0.5, 0.0],
+            }],
+            ..Default::default()
+        };
+        assert!(zones.validate().is_err());
+    }
+
+    #[test]
+    fn test_deserialize_zones() -> Result<()> {
+        let zones: StreamZones = toml::from_str(
+            r#"
+filter = true
+
+[[zones]]
+name = "door"
+polygon = [[0.0, 0.0], [0.5, 0.0], [0.5, 1.0], [0.0, 1.0]]
+"#,
+        )?;
+        assert_eq!(
+            zones,
+            StreamZones {
+                zones: vec![door()],
+                filter: true,
+                ..Default::default()
+            }
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_draw_zones_faintly() {
+        let mut frame = RgbImage::new(20, 20);
+        draw_zones_on_image(
+            &mut frame,
+            &StreamZones {
+                zones: vec![door()],
+                filter: false,
+                ..Default::default()
+            },
+        );
+
+        // The outline is drawn with a blended color
+        let pixel = frame.get_pixel(5, 0);
+        assert!(pixel[0] > 0 && pixel[0] < 255, "{pixel:?}");
+    }
+}